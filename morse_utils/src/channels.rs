@@ -0,0 +1,345 @@
+// Samples from several sensors watching the same lamp, e.g. the channels of an
+// RGB colour sensor or a pair of photodiodes, reduced to the single intensity
+// the rest of the pipeline works on.
+
+use crate::{calc_digital_cutoffs_by, Cutoffs, LightIntensity, MorseErr, ThresholdMethod, Time};
+use heapless::{ArrayLength, Vec};
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Combine {
+    Channel(usize),
+    // The single channel whose levels separate best
+    BestContrast,
+    // Every channel weighted by its signal over its noise, which for
+    // independent noise beats any one channel. The result is bright when the
+    // best single channel is, so pair it with `Polarity::Auto` if the
+    // channels disagree.
+    MaxRatio,
+    // `signal` minus `reference`, cancelling ambient light that both see
+    // equally, e.g. the red channel minus the green for a red lamp in a white
+    // lit room
+    Differential { signal: usize, reference: usize },
+}
+
+// Where the idle and keyed levels land after max ratio combining
+const COMBINED_IDLE: i64 = 0x4000;
+const COMBINED_SWING: i64 = 0x8000;
+// Differential output is re-centered here so it can go either way
+const DIFFERENTIAL_OFFSET: i64 = 0x8000;
+
+// Count, sum and sum of squares of one channel's levels, idle then keyed
+type ChannelStats = [(i64, i64, i64); 2];
+
+fn saturate(x: i64) -> LightIntensity {
+    x.clamp(0, LightIntensity::MAX as i64) as LightIntensity
+}
+
+// Every sample must have a level for `channel`
+fn check_channel<N>(
+    samples: &[(Time, Vec<LightIntensity, N>)],
+    channel: usize,
+) -> Result<(), MorseErr>
+where
+    N: ArrayLength<LightIntensity>,
+{
+    if samples.iter().any(|(_, levels)| channel >= levels.len()) {
+        return Err(MorseErr::NoSuchChannel);
+    }
+    Ok(())
+}
+
+fn project<C, N>(
+    samples: &[(Time, Vec<LightIntensity, N>)],
+    channel: usize,
+    out: &mut Vec<(Time, LightIntensity), C>,
+) -> Result<(), MorseErr>
+where
+    C: ArrayLength<(Time, LightIntensity)>,
+    N: ArrayLength<LightIntensity>,
+{
+    check_channel(samples, channel)?;
+    while out.pop().is_some() {}
+    for (time, levels) in samples {
+        out.push((*time, levels[channel]))
+            .map_err(|_| MorseErr::OutputFull)?;
+    }
+    Ok(())
+}
+
+// The channel with the best separated levels and its cutoffs. `scratch` holds
+// one channel at a time.
+pub fn best_channel<C, N>(
+    samples: &[(Time, Vec<LightIntensity, N>)],
+    scratch: &mut Vec<(Time, LightIntensity), C>,
+) -> Result<(usize, Cutoffs), MorseErr>
+where
+    C: ArrayLength<(Time, LightIntensity)>,
+    N: ArrayLength<LightIntensity>,
+{
+    let mut best: Option<(usize, Cutoffs)> = None;
+    for channel in 0..N::to_usize() {
+        project(samples, channel, scratch)?;
+        let cutoffs = match calc_digital_cutoffs_by(scratch, ThresholdMethod::Otsu) {
+            Ok(cutoffs) => cutoffs,
+            // A channel that never changes can't be the one
+            Err(_) => continue,
+        };
+        match best {
+            Some((_, b)) if b.separation >= cutoffs.separation => (),
+            _ => best = Some((channel, cutoffs)),
+        }
+    }
+    best.ok_or(MorseErr::NoContrast)
+}
+
+fn max_ratio<C, N>(
+    samples: &[(Time, Vec<LightIntensity, N>)],
+    out: &mut Vec<(Time, LightIntensity), C>,
+) -> Result<(), MorseErr>
+where
+    C: ArrayLength<(Time, LightIntensity)>,
+    N: ArrayLength<LightIntensity> + ArrayLength<ChannelStats> + ArrayLength<i64>,
+{
+    let channels = N::to_usize();
+    for channel in 0..channels {
+        check_channel(samples, channel)?;
+    }
+
+    // The best channel decides which samples are keyed, then every channel's
+    // levels are measured against that
+    let (best, cutoffs) = best_channel(samples, out)?;
+    let keyed = |levels: &Vec<LightIntensity, N>| levels[best] > cutoffs.threshold;
+
+    let mut stats: Vec<ChannelStats, N> = Vec::new();
+    while stats.push([(0, 0, 0); 2]).is_ok() {}
+    for (_, levels) in samples {
+        let class = keyed(levels) as usize;
+        for (channel, level) in levels.iter().enumerate() {
+            let s = &mut stats[channel][class];
+            let x = *level as i64;
+            s.0 += 1;
+            s.1 += x;
+            s.2 += x * x;
+        }
+    }
+
+    let mut idle: Vec<i64, N> = Vec::new();
+    let mut weights: Vec<i64, N> = Vec::new();
+    let mut full_swing = 0;
+    for channel in 0..channels {
+        let mean_and_variance = |(count, sum, squares): (i64, i64, i64)| {
+            let mean = sum / count.max(1);
+            (mean, (squares / count.max(1) - mean * mean).max(0))
+        };
+        let (idle_mean, idle_variance) = mean_and_variance(stats[channel][0]);
+        let (keyed_mean, keyed_variance) = mean_and_variance(stats[channel][1]);
+        // Signed, so a channel that dips when the lamp comes on still helps
+        let swing = keyed_mean - idle_mean;
+        let noise = ((idle_variance + keyed_variance) / 2).max(1);
+        let weight = swing * 1024 / noise;
+        // Neither can be full, both hold `channels`
+        let _ = idle.push(idle_mean);
+        let _ = weights.push(weight);
+        full_swing += weight * swing;
+    }
+    if full_swing <= 0 {
+        return Err(MorseErr::NoContrast);
+    }
+
+    while out.pop().is_some() {}
+    for (time, levels) in samples {
+        let combined: i64 = (0..channels)
+            .map(|channel| weights[channel] * (levels[channel] as i64 - idle[channel]))
+            .sum();
+        out.push((
+            *time,
+            saturate(COMBINED_IDLE + combined * COMBINED_SWING / full_swing),
+        ))
+        .map_err(|_| MorseErr::OutputFull)?;
+    }
+    Ok(())
+}
+
+// Reduces multi-channel samples to one intensity per sample in `out`, ready
+// for `calc_digital_cutoffs` and `convert`. `N` is the number of channels,
+// e.g. `U3`, and every sample holds a level for each.
+pub fn combine_channels<C, N>(
+    samples: &[(Time, Vec<LightIntensity, N>)],
+    method: Combine,
+    out: &mut Vec<(Time, LightIntensity), C>,
+) -> Result<(), MorseErr>
+where
+    C: ArrayLength<(Time, LightIntensity)>,
+    N: ArrayLength<LightIntensity> + ArrayLength<ChannelStats> + ArrayLength<i64>,
+{
+    match method {
+        Combine::Channel(channel) => project(samples, channel, out),
+        Combine::BestContrast => {
+            let (channel, _) = best_channel(samples, out)?;
+            project(samples, channel, out)
+        }
+        Combine::MaxRatio => max_ratio(samples, out),
+        Combine::Differential { signal, reference } => {
+            check_channel(samples, signal)?;
+            check_channel(samples, reference)?;
+            while out.pop().is_some() {}
+            for (time, levels) in samples {
+                let difference = levels[signal] as i64 - levels[reference] as i64;
+                out.push((*time, saturate(difference + DIFFERENTIAL_OFFSET)))
+                    .map_err(|_| MorseErr::OutputFull)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::consts::*;
+
+    // Uniform noise in -amplitude..=amplitude from a xorshift generator
+    fn helper_noise(seed: &mut u32, amplitude: i64) -> i64 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        (*seed as i64) % (2 * amplitude + 1) - amplitude
+    }
+
+    // Keyed on and off every 20 samples
+    fn helper_keyed(i: usize) -> bool {
+        (i / 20) % 2 == 1
+    }
+
+    // Samples on the wrong side of the threshold, whichever way round the
+    // levels go
+    fn helper_misread(samples: &[(Time, LightIntensity)]) -> usize {
+        let cutoffs = calc_digital_cutoffs_by(samples, ThresholdMethod::Otsu).unwrap();
+        let wrong = samples
+            .iter()
+            .enumerate()
+            .filter(|(i, (_, li))| helper_keyed(*i) != (*li > cutoffs.threshold))
+            .count();
+        wrong.min(samples.len() - wrong)
+    }
+
+    #[test]
+    fn test_best_contrast() {
+        // A photodiode aimed well at the lamp, one aimed badly, and one that's
+        // only picking up noise
+        let mut seed = 3;
+        let mut samples: Vec<(Time, Vec<LightIntensity, U3>), U256> = Vec::new();
+        for i in 0..200 {
+            let key = helper_keyed(i) as i64;
+            let levels = [
+                500 + 80 * key + helper_noise(&mut seed, 30),
+                500 + 800 * key + helper_noise(&mut seed, 30),
+                500 + helper_noise(&mut seed, 30),
+            ];
+            samples
+                .push((
+                    i as Time,
+                    Vec::from_slice(&[levels[0] as u16, levels[1] as u16, levels[2] as u16])
+                        .unwrap(),
+                ))
+                .unwrap();
+        }
+
+        let mut out: Vec<(Time, LightIntensity), U256> = Vec::new();
+        assert_eq!(1, best_channel(&samples, &mut out).unwrap().0);
+        combine_channels(&samples, Combine::BestContrast, &mut out).unwrap();
+        assert_eq!(samples[25].1[1], out[25].1);
+    }
+
+    #[test]
+    fn test_max_ratio() {
+        // Two equally noisy views of the lamp, one of which sees it dim
+        // when keyed, as behind a shutter
+        let mut seed = 11;
+        let mut samples: Vec<(Time, Vec<LightIntensity, U2>), U256> = Vec::new();
+        for i in 0..200 {
+            let key = helper_keyed(i) as i64;
+            let levels = [
+                1000 + 300 * key + helper_noise(&mut seed, 200),
+                2000 - 300 * key + helper_noise(&mut seed, 200),
+            ];
+            samples
+                .push((
+                    i as Time,
+                    Vec::from_slice(&[levels[0] as u16, levels[1] as u16]).unwrap(),
+                ))
+                .unwrap();
+        }
+
+        let mut out: Vec<(Time, LightIntensity), U256> = Vec::new();
+        let mut single = samples.len();
+        for channel in 0..2 {
+            combine_channels(&samples, Combine::Channel(channel), &mut out).unwrap();
+            single = single.min(helper_misread(&out));
+        }
+        combine_channels(&samples, Combine::MaxRatio, &mut out).unwrap();
+        let combined = helper_misread(&out);
+        assert!(combined * 3 < single * 2, "{} vs {}", combined, single);
+    }
+
+    #[test]
+    fn test_differential() {
+        // A red lamp in a room whose white lighting switches between levels
+        // far bigger than the lamp itself
+        let mut seed = 5;
+        let mut samples: Vec<(Time, Vec<LightIntensity, U3>), U256> = Vec::new();
+        for i in 0..240 {
+            let key = helper_keyed(i) as i64;
+            let room = if (i / 37) % 2 == 0 { 1000 } else { 3000 };
+            let levels = [
+                room + 600 * key + helper_noise(&mut seed, 20),
+                room + 50 * key + helper_noise(&mut seed, 20),
+                room + helper_noise(&mut seed, 20),
+            ];
+            samples
+                .push((
+                    i as Time,
+                    Vec::from_slice(&[levels[0] as u16, levels[1] as u16, levels[2] as u16])
+                        .unwrap(),
+                ))
+                .unwrap();
+        }
+
+        let mut out: Vec<(Time, LightIntensity), U256> = Vec::new();
+        // The red channel alone follows the room lights
+        combine_channels(&samples, Combine::Channel(0), &mut out).unwrap();
+        assert!(helper_misread(&out) > 50);
+        combine_channels(
+            &samples,
+            Combine::Differential {
+                signal: 0,
+                reference: 1,
+            },
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(0, helper_misread(&out));
+
+        assert_eq!(
+            Err(MorseErr::NoSuchChannel),
+            combine_channels(&samples, Combine::Channel(3), &mut out)
+        );
+        assert_eq!(
+            Err(MorseErr::NoSuchChannel),
+            combine_channels(
+                &samples,
+                Combine::Differential {
+                    signal: 0,
+                    reference: 3,
+                },
+                &mut out,
+            )
+        );
+        // A sample that lost its third level
+        samples[100].1.pop();
+        assert_eq!(
+            Err(MorseErr::NoSuchChannel),
+            combine_channels(&samples, Combine::MaxRatio, &mut out)
+        );
+    }
+}
@@ -0,0 +1,314 @@
+// The unit search and decoder in 16-bit durations and 32-bit scores, for
+// targets like the ATmega328P where every `i64` add and compare is a library
+// call. Enabled by the `compact` feature.
+//
+// Results match the `Time` path with the `Absolute` scorer as long as no
+// expected length passes `u16::MAX` ticks, e.g. a 7 unit word space with a
+// unit under 9 seconds at millisecond ticks. Past that, lengths saturate
+// instead of wrapping, so a stuck key still reads as a very long event.
+
+use crate::{
+    decode_symbols, LightState, MorseCandidate, MorseErr, Scored, TimedLightEvent, TimingModel,
+};
+use heapless::consts::U8;
+use heapless::{ArrayLength, String, Vec};
+
+pub type Duration = u16;
+pub type Score = u32;
+
+fn expected(units: u32, unit: Duration, subdivisions: u32) -> Duration {
+    (units * unit as u32 / subdivisions).min(Duration::MAX as u32) as Duration
+}
+
+fn distance(a: Duration, b: Duration) -> Duration {
+    // Not `abs_diff`, which is newer than the AVR toolchain
+    a.max(b) - a.min(b)
+}
+
+// A timing model with each candidate's weighted length worked out ahead of
+// time, so scoring an event needs no 64-bit math
+pub struct CompactModel<'a> {
+    model: TimingModel<'a>,
+    candidates: Vec<(LightState, u32), U8>,
+    subdivisions: u32,
+}
+
+impl<'a> CompactModel<'a> {
+    // Fails for models of more than 8 elements
+    pub fn new(model: TimingModel<'a>) -> Result<Self, MorseErr> {
+        let mut candidates = Vec::new();
+        for element in model.elements {
+            let units = model.weighted_units(&element.candidate).max(0) as u32;
+            candidates
+                .push((element.candidate.light_state, units))
+                .map_err(|_| MorseErr::OutputFull)?;
+        }
+        Ok(CompactModel {
+            model,
+            candidates,
+            subdivisions: model.subdivisions.clamp(1, u16::MAX as i64) as u32,
+        })
+    }
+
+    pub fn standard() -> CompactModel<'static> {
+        CompactModel {
+            model: TimingModel::standard(),
+            candidates: TimingModel::standard()
+                .elements
+                .iter()
+                .map(|e| (e.candidate.light_state, e.candidate.units as u32))
+                .collect(),
+            subdivisions: 1,
+        }
+    }
+
+    fn expected(&self, units: u32, unit: Duration) -> Duration {
+        expected(units, unit, self.subdivisions)
+    }
+
+    // Index of the closest candidate, and how far off it is
+    fn best(&self, event: &TimedLightEvent<Duration>, unit: Duration) -> Option<(usize, Duration)> {
+        let mut best: Option<(usize, Duration)> = None;
+        for (i, (light_state, units)) in self.candidates.iter().enumerate() {
+            if *light_state != event.light_state {
+                continue;
+            }
+            let error = distance(event.duration, self.expected(*units, unit));
+            match best {
+                Some((_, b)) if error >= b => (),
+                _ => best = Some((i, error)),
+            }
+        }
+        best
+    }
+}
+
+// `crate::calc_error` against the standard model, whose candidates are a few
+// units long
+pub fn calc_error(
+    event: &TimedLightEvent<Duration>,
+    candidate: &MorseCandidate,
+    unit_millis: Duration,
+) -> Option<Duration> {
+    if event.light_state == candidate.light_state {
+        Some(distance(
+            event.duration,
+            expected(candidate.units as u32, unit_millis, 1),
+        ))
+    } else {
+        None
+    }
+}
+
+pub fn best_error<'a>(
+    event: &TimedLightEvent<Duration>,
+    unit_millis: Duration,
+    model: &CompactModel<'a>,
+) -> Result<Scored<&'a MorseCandidate>, MorseErr> {
+    let (i, error) = model.best(event, unit_millis).ok_or(MorseErr::TooFewTLEs)?;
+    Ok(Scored {
+        item: &model.model.elements[i].candidate,
+        score: error as i64,
+    })
+}
+
+fn score_unit(
+    unit_millis: Duration,
+    timings: &[TimedLightEvent<Duration>],
+    model: &CompactModel,
+) -> Result<Score, MorseErr> {
+    let mut sum: Score = 0;
+    for event in timings {
+        let (_, error) = model.best(event, unit_millis).ok_or(MorseErr::TooFewTLEs)?;
+        sum = sum.saturating_add(error as Score);
+    }
+    Ok(sum)
+}
+
+pub fn score_possible_unit_millis(
+    unit_millis: Duration,
+    timings: &[TimedLightEvent<Duration>],
+) -> Result<Scored<Duration>, MorseErr> {
+    score_possible_unit_millis_by(unit_millis, timings, &CompactModel::standard())
+}
+
+pub fn score_possible_unit_millis_by(
+    unit_millis: Duration,
+    timings: &[TimedLightEvent<Duration>],
+    model: &CompactModel,
+) -> Result<Scored<Duration>, MorseErr> {
+    Ok(Scored {
+        item: unit_millis,
+        score: score_unit(unit_millis, timings, model)? as i64,
+    })
+}
+
+pub fn estimate_unit_time(
+    timings: &[TimedLightEvent<Duration>],
+    min_millis: Duration,
+    max_millis: Duration,
+) -> Result<Scored<Duration>, MorseErr> {
+    estimate_unit_time_by(timings, min_millis, max_millis, &CompactModel::standard())
+}
+
+// Same search as `crate::estimate_unit_time_by`, ties going to the longer unit
+pub fn estimate_unit_time_by(
+    timings: &[TimedLightEvent<Duration>],
+    min_millis: Duration,
+    max_millis: Duration,
+    model: &CompactModel,
+) -> Result<Scored<Duration>, MorseErr> {
+    let mut best: Option<(Duration, Score)> = None;
+    for unit in min_millis..max_millis {
+        let score = score_unit(unit, timings, model)?;
+        match best {
+            Some((_, b)) if b < score => (),
+            _ => best = Some((unit, score)),
+        }
+    }
+    let (item, score) = best.ok_or(MorseErr::TooFewTLEs)?;
+    Ok(Scored {
+        item,
+        score: score as i64,
+    })
+}
+
+pub fn decode_events<C>(
+    events: &[TimedLightEvent<Duration>],
+    unit_millis: Duration,
+    model: &CompactModel,
+    text: &mut String<C>,
+) -> Result<(), MorseErr>
+where
+    C: ArrayLength<u8>,
+{
+    let symbols = events.iter().map(|event| {
+        let (i, _) = model.best(event, unit_millis).ok_or(MorseErr::TooFewTLEs)?;
+        Ok(model.model.elements[i].morse)
+    });
+    decode_symbols(symbols, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Absolute, Time};
+    use heapless::consts::*;
+
+    fn helper_noise(seed: &mut u32, amplitude: u16) -> i32 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        (*seed % (2 * amplitude as u32 + 1)) as i32 - amplitude as i32
+    }
+
+    // "CQ TEST", keyed with jitter of up to `jitter` either way
+    fn helper_jittered(seed: &mut u32, unit: u16, jitter: u16) -> Vec<TimedLightEvent<u16>, U64> {
+        let units = [
+            3, 1, 1, 1, 3, 1, 1, 3, 3, 1, 3, 1, 1, 1, 3, 7, 3, 3, 1, 3, 1, 1, 1, 1, 1, 3, 3,
+        ];
+        let mut events = Vec::new();
+        for (i, n) in units.iter().enumerate() {
+            let light_state = if i % 2 == 0 {
+                LightState::Light
+            } else {
+                LightState::Dark
+            };
+            let duration = n * unit as i32 + helper_noise(seed, jitter);
+            events
+                .push(TimedLightEvent {
+                    light_state,
+                    duration: duration.max(1) as u16,
+                })
+                .unwrap();
+        }
+        events
+    }
+
+    fn helper_widen(events: &[TimedLightEvent<u16>]) -> Vec<TimedLightEvent, U64> {
+        events
+            .iter()
+            .map(|e| TimedLightEvent {
+                light_state: e.light_state,
+                duration: e.duration as Time,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_matches_wide_path() {
+        let mut seed = 7;
+        for &(unit, jitter) in [(12, 4), (60, 25), (100, 45), (250, 100), (1000, 300)].iter() {
+            let events = helper_jittered(&mut seed, unit, jitter);
+            let wide_events = helper_widen(&events);
+            for (event, wide_event) in events.iter().zip(wide_events.iter()) {
+                for element in TimingModel::standard().elements {
+                    let candidate = &element.candidate;
+                    assert_eq!(
+                        crate::calc_error(wide_event, candidate, unit as Time),
+                        calc_error(event, candidate, unit).map(|e| e as i64)
+                    );
+                }
+            }
+            for guess in (unit / 2..unit * 2).step_by(unit as usize / 8) {
+                let wide = crate::score_possible_unit_millis(guess as Time, &wide_events).unwrap();
+                let compact = score_possible_unit_millis(guess, &events).unwrap();
+                assert_eq!(
+                    (wide.item, wide.score),
+                    (compact.item as Time, compact.score)
+                );
+            }
+
+            let (min, max) = (unit / 4, unit * 2);
+            let compact = estimate_unit_time(&events, min, max).unwrap();
+            let wide = crate::estimate_unit_time(&wide_events, min as Time, max as Time).unwrap();
+            assert_eq!(
+                (wide.item, wide.score),
+                (compact.item as Time, compact.score)
+            );
+
+            let mut compact_text: String<U16> = String::new();
+            let mut wide_text: String<U16> = String::new();
+            decode_events(
+                &events,
+                compact.item,
+                &CompactModel::standard(),
+                &mut compact_text,
+            )
+            .unwrap();
+            crate::decode_events(&wide_events, wide.item, &mut wide_text).unwrap();
+            assert_eq!(wide_text, compact_text);
+        }
+
+        let american = TimingModel::american().with_weight(1);
+        let compact_american = CompactModel::new(american).unwrap();
+        let events = helper_jittered(&mut seed, 80, 20);
+        for (event, wide_event) in events.iter().zip(helper_widen(&events).iter()) {
+            assert_eq!(
+                crate::best_error_by(wide_event, 80, &american, &Absolute),
+                best_error(event, 80, &compact_american)
+            );
+        }
+    }
+
+    #[test]
+    fn test_saturates() {
+        // A key held down for over a minute reads as a dash whose expected
+        // length saturated at the same point
+        let events = [
+            TimedLightEvent {
+                light_state: LightState::Light,
+                duration: u16::MAX,
+            },
+            TimedLightEvent {
+                light_state: LightState::Dark,
+                duration: 100,
+            },
+        ];
+        let model = CompactModel::standard();
+        assert_eq!(Ok(0), score_unit(30000, &events[..1], &model));
+        let mut text: String<U8> = String::new();
+        decode_events(&events, 30000, &model, &mut text).unwrap();
+        assert_eq!("T", text.as_str());
+    }
+}
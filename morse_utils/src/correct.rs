@@ -0,0 +1,409 @@
+// Correcting letters that don't decode. Instead of giving up with '?', each
+// group is matched to the nearest code by an edit distance where flipping a
+// dot to a dash costs however close that element came to being the other, and
+// a missing or extra element costs `indel_cost`. Gaps that were close to the
+// line between an inner space and a letter space can also be flipped, which
+// splits one group into two or merges two into one.
+//
+// This is only for decoding a whole capture. `StreamDecoder` commits to each
+// letter as its gap goes by, so it still gives '?' for unknown codes.
+
+use crate::time::TickCount;
+use crate::{
+    best_error_by, calc_error_by, Absolute, CodeTable, LightState, Morse, MorseErr, ScoreFn,
+    TimedLightEvent, TimingModel,
+};
+use heapless::consts::{U64, U65, U8};
+use heapless::{ArrayLength, Vec};
+
+// Longest group worth matching: the longest code plus one extra element
+const MAX_GROUP: usize = 8;
+// The margin of an event with nothing on the other side to confuse it with
+const NO_ALTERNATIVE: i64 = i64::MAX / 4;
+
+// How an event was read, and how much worse the best reading on the other side
+// of the dot/dash or inner/letter space line fit it
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Reading {
+    pub morse: Morse,
+    pub margin: i64,
+}
+
+impl Reading {
+    // A dash, or a gap between letters
+    fn is_long(&self) -> bool {
+        is_long(self.morse)
+    }
+}
+
+fn is_long(morse: Morse) -> bool {
+    matches!(
+        morse,
+        Morse::Dash | Morse::LongDash | Morse::LetterSpace | Morse::WordSpace
+    )
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Correction {
+    pub c: char,
+    // 0 for a letter read as sent, otherwise the summed margins and
+    // `indel_cost`s it took to reach a valid code
+    pub cost: i64,
+}
+
+pub fn read_event<S: ScoreFn, D: TickCount>(
+    event: &TimedLightEvent<D>,
+    unit_millis: D,
+    model: &TimingModel,
+    scorer: &S,
+) -> Result<Reading, MorseErr> {
+    let best = best_error_by(event, unit_millis, model, scorer)?;
+    let morse = model.morse(best.item);
+    let runner_up = model
+        .elements
+        .iter()
+        .filter(|e| is_long(e.morse) != is_long(morse))
+        .filter_map(|e| calc_error_by(event, &e.candidate, unit_millis, model, scorer))
+        .min();
+    Ok(Reading {
+        morse,
+        margin: runner_up.map_or(NO_ALTERNATIVE, |score| score - best.score),
+    })
+}
+
+// The code in `table` closest to `code`, ties going to the earlier code
+pub fn nearest_code(code: &[Reading], table: &CodeTable, indel_cost: i64) -> Option<Correction> {
+    if code.is_empty() || code.len() > MAX_GROUP {
+        return None;
+    }
+    let mut best: Option<Correction> = None;
+    for (pattern, c) in table.codes.iter() {
+        let pattern = pattern.as_bytes();
+        if pattern.len() > MAX_GROUP {
+            continue;
+        }
+        // Edit distance from the first i elements to the first j of pattern
+        let mut distance = [[0i64; MAX_GROUP + 1]; MAX_GROUP + 1];
+        for (j, d) in distance[0].iter_mut().enumerate() {
+            *d = j as i64 * indel_cost;
+        }
+        for (i, reading) in code.iter().enumerate() {
+            distance[i + 1][0] = (i as i64 + 1).saturating_mul(indel_cost);
+            for (j, b) in pattern.iter().enumerate() {
+                let flip = match (*b == b'-') == reading.is_long() {
+                    true => 0,
+                    false => reading.margin,
+                };
+                distance[i + 1][j + 1] = (distance[i][j].saturating_add(flip))
+                    .min(distance[i][j + 1].saturating_add(indel_cost))
+                    .min(distance[i + 1][j].saturating_add(indel_cost));
+            }
+        }
+        let cost = distance[code.len()][pattern.len()];
+        match best {
+            Some(b) if b.cost <= cost => (),
+            _ => best = Some(Correction { c: *c, cost }),
+        }
+    }
+    best
+}
+
+// Splits one word's elements into letters, each light in `lights` paired with
+// the gap after it
+fn correct_word<C>(
+    lights: &[(Reading, Reading)],
+    table: &CodeTable,
+    indel_cost: i64,
+    letters: &mut Vec<Correction, C>,
+) -> Result<(), MorseErr>
+where
+    C: ArrayLength<Correction>,
+{
+    // Cheapest way to read the first j lights, and the letter that ends it,
+    // for every j up to and including a full word's worth
+    let mut cheapest: Vec<(i64, usize, Correction), U65> = Vec::new();
+    let unreadable = Correction { c: '?', cost: 0 };
+    cheapest
+        .push((0, 0, unreadable))
+        .map_err(|_| MorseErr::TooManyTLEs)?;
+    for end in 1..=lights.len() {
+        let mut best: Option<(i64, usize, Correction)> = None;
+        for start in end.saturating_sub(MAX_GROUP)..end {
+            let group: Vec<Reading, U8> = lights[start..end].iter().map(|(l, _)| *l).collect();
+            let mut letter = match nearest_code(&group, table, indel_cost) {
+                Some(letter) => letter,
+                None => continue,
+            };
+            // Gaps inside the letter that were read as letter spaces, and the
+            // one before it if that was read as an inner space
+            for (_, gap) in lights[start..end - 1].iter() {
+                if gap.is_long() {
+                    letter.cost = letter.cost.saturating_add(gap.margin);
+                }
+            }
+            if start > 0 && !lights[start - 1].1.is_long() {
+                letter.cost = letter.cost.saturating_add(lights[start - 1].1.margin);
+            }
+            let total = cheapest[start].0.saturating_add(letter.cost);
+            match best {
+                Some((b, _, _)) if b <= total => (),
+                _ => best = Some((total, start, letter)),
+            }
+        }
+        let best = best.unwrap_or((NO_ALTERNATIVE, end - 1, unreadable));
+        cheapest.push(best).map_err(|_| MorseErr::TooManyTLEs)?;
+    }
+
+    // Walk back from the end to find which letters were used
+    let first = letters.len();
+    let mut end = lights.len();
+    while end > 0 {
+        let (_, start, letter) = cheapest[end];
+        letters.push(letter).map_err(|_| MorseErr::OutputFull)?;
+        end = start;
+    }
+    letters[first..].reverse();
+    Ok(())
+}
+
+pub fn decode_corrected<C, D>(
+    events: &[TimedLightEvent<D>],
+    unit_millis: D,
+    letters: &mut Vec<Correction, C>,
+) -> Result<(), MorseErr>
+where
+    C: ArrayLength<Correction>,
+    D: TickCount,
+{
+    decode_corrected_by(
+        events,
+        unit_millis,
+        &TimingModel::standard(),
+        &Absolute,
+        &CodeTable::standard(),
+        unit_millis.ticks(),
+        letters,
+    )
+}
+
+// Appends the letters spelled out by `events` like `decode_events`, with a ' '
+// between words, but each letter corrected to the nearest code in `table`.
+// Word spaces are kept as read; only gaps inside words are reconsidered.
+pub fn decode_corrected_by<C, S, D>(
+    events: &[TimedLightEvent<D>],
+    unit_millis: D,
+    model: &TimingModel,
+    scorer: &S,
+    table: &CodeTable,
+    indel_cost: i64,
+    letters: &mut Vec<Correction, C>,
+) -> Result<(), MorseErr>
+where
+    C: ArrayLength<Correction>,
+    S: ScoreFn,
+    D: TickCount,
+{
+    // Each light and the gap after it, until a word space
+    let mut word: Vec<(Reading, Reading), U64> = Vec::new();
+    // Two lights in a row have nothing between them to be a letter space
+    let no_gap = Reading {
+        morse: Morse::TinySpace,
+        margin: NO_ALTERNATIVE,
+    };
+    for event in events {
+        let reading = read_event(event, unit_millis, model, scorer)?;
+        match event.light_state {
+            LightState::Light => word
+                .push((reading, no_gap))
+                .map_err(|_| MorseErr::TooManyTLEs)?,
+            LightState::Dark if reading.morse == Morse::WordSpace => {
+                flush_word(&mut word, table, indel_cost, letters)?
+            }
+            LightState::Dark => {
+                // Dark before the first light has no light to follow
+                if let Some((_, gap)) = word.last_mut() {
+                    *gap = reading;
+                }
+            }
+        }
+    }
+    flush_word(&mut word, table, indel_cost, letters)
+}
+
+fn flush_word<C>(
+    word: &mut Vec<(Reading, Reading), U64>,
+    table: &CodeTable,
+    indel_cost: i64,
+    letters: &mut Vec<Correction, C>,
+) -> Result<(), MorseErr>
+where
+    C: ArrayLength<Correction>,
+{
+    if word.is_empty() {
+        return Ok(());
+    }
+    if !letters.is_empty() {
+        letters
+            .push(Correction { c: ' ', cost: 0 })
+            .map_err(|_| MorseErr::OutputFull)?;
+    }
+    correct_word(word, table, indel_cost, letters)?;
+    while word.pop().is_some() {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_events, Time};
+    use heapless::consts::*;
+    use heapless::String;
+
+    // Alternating light and dark, light first
+    fn helper_events(durations: &[Time]) -> Vec<TimedLightEvent, U64> {
+        durations
+            .iter()
+            .enumerate()
+            .map(|(i, duration)| TimedLightEvent {
+                light_state: if i % 2 == 0 {
+                    LightState::Light
+                } else {
+                    LightState::Dark
+                },
+                duration: *duration,
+            })
+            .collect()
+    }
+
+    fn helper_text(letters: &[Correction]) -> String<U32> {
+        let mut text = String::new();
+        for letter in letters {
+            text.push(letter.c).unwrap();
+        }
+        text
+    }
+
+    #[test]
+    fn test_clean_matches_decode_events() {
+        // PARIS PARIS, every letter already valid
+        let durations = [
+            100, 100, 300, 100, 300, 100, 100, 300, 100, 100, 300, 300, 100, 100, 300, 100, 100,
+            300, 100, 100, 100, 300, 100, 100, 100, 100, 100, 700, 100, 100, 300, 100, 300, 100,
+            100, 300, 100, 100, 300, 300, 100, 100, 300, 100, 100, 300, 100, 100, 100, 300, 100,
+            100, 100, 100, 100,
+        ];
+        let events = helper_events(&durations);
+        let mut letters: Vec<Correction, U16> = Vec::new();
+        decode_corrected(&events, 100, &mut letters).unwrap();
+        let mut text: String<U32> = String::new();
+        decode_events(&events, 100, &mut text).unwrap();
+        assert_eq!(text, helper_text(&letters));
+        assert!(letters.iter().all(|l| l.cost == 0));
+    }
+
+    #[test]
+    fn test_flip_marginal_elements() {
+        // "?" sent as ..--.. with both dashes short enough to read as dots,
+        // which is no letter at all
+        let events = helper_events(&[100, 100, 100, 100, 190, 100, 190, 100, 100, 100, 100]);
+        let mut text: String<U8> = String::new();
+        decode_events(&events, 100, &mut text).unwrap();
+        assert_eq!("?", text.as_str());
+
+        let readings: Vec<Reading, U8> = events
+            .iter()
+            .step_by(2)
+            .map(|e| read_event(e, 100, &TimingModel::standard(), &Absolute).unwrap())
+            .collect();
+        assert!(readings.iter().all(|r| r.morse == Morse::Dot));
+        let nearest = nearest_code(&readings, &CodeTable::standard(), 100);
+        assert_eq!(Some(Correction { c: '?', cost: 40 }), nearest);
+    }
+
+    #[test]
+    fn test_split_and_merge_gaps() {
+        // "5E" with the letter space cut short to look like an inner space
+        let events = helper_events(&[100, 100, 100, 100, 100, 100, 100, 100, 100, 190, 100]);
+        let mut letters: Vec<Correction, U8> = Vec::new();
+        decode_corrected(&events, 100, &mut letters).unwrap();
+        assert_eq!(
+            &[
+                Correction { c: '5', cost: 0 },
+                Correction { c: 'E', cost: 20 }
+            ][..],
+            &letters[..]
+        );
+
+        // "$" with its last inner space stretched to look like a letter space
+        let events = helper_events(&[
+            100, 100, 100, 100, 100, 100, 300, 100, 100, 100, 100, 210, 300, 700, 100,
+        ]);
+        let mut letters: Vec<Correction, U8> = Vec::new();
+        decode_corrected(&events, 100, &mut letters).unwrap();
+        assert_eq!(
+            &[
+                Correction { c: '$', cost: 20 },
+                Correction { c: ' ', cost: 0 },
+                Correction { c: 'E', cost: 0 }
+            ][..],
+            &letters[..]
+        );
+    }
+
+    #[test]
+    fn test_longest_word() {
+        // 64 lights is as many as a word holds, here all E
+        let mut events: Vec<TimedLightEvent, U256> = Vec::new();
+        for i in 0..64 {
+            if i > 0 {
+                events
+                    .push(TimedLightEvent {
+                        light_state: LightState::Dark,
+                        duration: 300,
+                    })
+                    .unwrap();
+            }
+            events
+                .push(TimedLightEvent {
+                    light_state: LightState::Light,
+                    duration: 100,
+                })
+                .unwrap();
+        }
+        let mut letters: Vec<Correction, U64> = Vec::new();
+        decode_corrected(&events, 100, &mut letters).unwrap();
+        assert_eq!(64, letters.len());
+        assert!(letters.iter().all(|l| l.c == 'E'));
+
+        events
+            .push(TimedLightEvent {
+                light_state: LightState::Dark,
+                duration: 300,
+            })
+            .unwrap();
+        events
+            .push(TimedLightEvent {
+                light_state: LightState::Light,
+                duration: 100,
+            })
+            .unwrap();
+        let mut letters: Vec<Correction, U128> = Vec::new();
+        assert_eq!(
+            Err(MorseErr::TooManyTLEs),
+            decode_corrected(&events, 100, &mut letters)
+        );
+    }
+
+    #[test]
+    fn test_nearest_code_indels() {
+        let dash = Reading {
+            morse: Morse::Dash,
+            margin: 200,
+        };
+        // Eight dashes are three too many for a zero
+        let nearest = nearest_code(&[dash; 8], &CodeTable::standard(), 50);
+        assert_eq!(Some(Correction { c: '0', cost: 150 }), nearest);
+        assert_eq!(None, nearest_code(&[dash; 9], &CodeTable::standard(), 50));
+        assert_eq!(None, nearest_code(&[], &CodeTable::standard(), 50));
+    }
+}
@@ -0,0 +1,345 @@
+// Conditioning for raw light samples before they're split into events. Every
+// stage works one sample at a time with a fixed amount of state, so the same
+// chain can run over a buffered capture or live on the Uno.
+
+use crate::{LightIntensity, Time};
+use heapless::{ArrayLength, Vec};
+
+pub trait Filter {
+    fn process(&mut self, sample: LightIntensity) -> LightIntensity;
+
+    fn then<F>(self, next: F) -> Chain<Self, F>
+    where
+        Self: Sized,
+        F: Filter,
+    {
+        Chain {
+            first: self,
+            second: next,
+        }
+    }
+}
+
+pub struct Chain<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> Filter for Chain<A, B>
+where
+    A: Filter,
+    B: Filter,
+{
+    fn process(&mut self, sample: LightIntensity) -> LightIntensity {
+        self.second.process(self.first.process(sample))
+    }
+}
+
+// Runs `filter` over a whole capture in place
+pub fn filter_samples<F: Filter>(samples: &mut [(Time, LightIntensity)], filter: &mut F) {
+    for (_, li) in samples.iter_mut() {
+        *li = filter.process(*li);
+    }
+}
+
+fn saturate(x: i32) -> LightIntensity {
+    x.clamp(0, LightIntensity::MAX as i32) as LightIntensity
+}
+
+// Fixed size window of the most recent samples
+struct Window<N: ArrayLength<LightIntensity>> {
+    samples: Vec<LightIntensity, N>,
+    next: usize,
+}
+
+impl<N: ArrayLength<LightIntensity>> Window<N> {
+    // A window has to hold at least one sample
+    fn new() -> Option<Self> {
+        if N::USIZE == 0 {
+            return None;
+        }
+        Some(Window {
+            samples: Vec::new(),
+            next: 0,
+        })
+    }
+
+    // Returns the sample that fell out of the window, if it was full
+    fn push(&mut self, sample: LightIntensity) -> Option<LightIntensity> {
+        if self.samples.push(sample).is_ok() {
+            return None;
+        }
+        let oldest = core::mem::replace(&mut self.samples[self.next], sample);
+        self.next = (self.next + 1) % self.samples.len();
+        Some(oldest)
+    }
+}
+
+pub struct MovingAverage<N: ArrayLength<LightIntensity>> {
+    window: Window<N>,
+    sum: u32,
+}
+
+impl<N: ArrayLength<LightIntensity>> MovingAverage<N> {
+    // None for an empty window
+    pub fn new() -> Option<Self> {
+        Some(MovingAverage {
+            window: Window::new()?,
+            sum: 0,
+        })
+    }
+}
+
+impl<N: ArrayLength<LightIntensity>> Filter for MovingAverage<N> {
+    fn process(&mut self, sample: LightIntensity) -> LightIntensity {
+        self.sum += sample as u32;
+        if let Some(oldest) = self.window.push(sample) {
+            self.sum -= oldest as u32;
+        }
+        (self.sum / self.window.samples.len() as u32) as LightIntensity
+    }
+}
+
+// Knocks out spikes shorter than half the window without smearing edges
+pub struct Median<N: ArrayLength<LightIntensity>> {
+    window: Window<N>,
+}
+
+impl<N: ArrayLength<LightIntensity>> Median<N> {
+    // None for an empty window
+    pub fn new() -> Option<Self> {
+        Some(Median {
+            window: Window::new()?,
+        })
+    }
+}
+
+impl<N: ArrayLength<LightIntensity>> Filter for Median<N> {
+    fn process(&mut self, sample: LightIntensity) -> LightIntensity {
+        self.window.push(sample);
+        let mut sorted = self.window.samples.clone();
+        sorted.sort_unstable();
+        sorted[sorted.len() / 2]
+    }
+}
+
+// Single pole IIR, y += (x - y) / 2^shift. The state keeps 8 extra bits so
+// small steps aren't lost to rounding.
+pub struct LowPass {
+    shift: u8,
+    state: Option<i32>,
+}
+
+impl LowPass {
+    // None for a shift as wide as the 32-bit state or wider
+    pub fn new(shift: u8) -> Option<Self> {
+        if shift >= 32 {
+            return None;
+        }
+        Some(LowPass { shift, state: None })
+    }
+}
+
+impl Filter for LowPass {
+    fn process(&mut self, sample: LightIntensity) -> LightIntensity {
+        let x = (sample as i32) << 8;
+        let y = match self.state {
+            Some(y) => y + ((x - y) >> self.shift),
+            None => x,
+        };
+        self.state = Some(y);
+        saturate(y >> 8)
+    }
+}
+
+// Subtracts a slowly tracking baseline, e.g. ambient light drifting over a
+// long capture, and re-centers the result on `offset`
+pub struct DcRemoval {
+    baseline: LowPass,
+    offset: LightIntensity,
+}
+
+impl DcRemoval {
+    // None for a shift `LowPass` would reject
+    pub fn new(shift: u8, offset: LightIntensity) -> Option<Self> {
+        Some(DcRemoval {
+            baseline: LowPass::new(shift)?,
+            offset,
+        })
+    }
+}
+
+impl Filter for DcRemoval {
+    fn process(&mut self, sample: LightIntensity) -> LightIntensity {
+        let baseline = self.baseline.process(sample);
+        saturate(sample as i32 - baseline as i32 + self.offset as i32)
+    }
+}
+
+// Coefficients are in 1/2^12ths, small enough that a whole sample times a
+// coefficient fits in 32 bits. The coefficients are worked out once with
+// 64-bit math, but filtering each sample only needs 32.
+const Q12: i64 = 1 << 12;
+// Pole radius, which sets the notch width to roughly 1.6% of the sample rate
+const NOTCH_RADIUS: i64 = Q12 * 95 / 100;
+
+// sin(2 pi turns) with the turns in 1/2^16ths, from Bhaskara's approximation
+fn sin_q12(turns: i64) -> i64 {
+    let t = turns & 0xFFFF;
+    let (t, sign) = if t < 0x8000 { (t, 1) } else { (t - 0x8000, -1) };
+    // sin(2 pi t) ~= 32 q / (5 - 8 q) where q = t (1 - 2 t) on [0, 1/2]
+    let q = (t * (0x10000 - 2 * t)) >> 16;
+    sign * 32 * q * Q12 / (5 * 0x10000 - 8 * q)
+}
+
+fn cos_q12(turns: i64) -> i64 {
+    sin_q12(turns + 0x4000)
+}
+
+// Second order notch, for taking out lamp flicker. Mains powered lights
+// flicker at twice the mains frequency. Frequencies above half the sample rate
+// alias down, and the notch follows them there.
+pub struct Notch {
+    b0: i32,
+    b1: i32,
+    a1: i32,
+    a2: i32,
+    x: [i32; 2],
+    y: [i32; 2],
+    primed: bool,
+}
+
+impl Notch {
+    pub fn new(sample_rate_hz: u32, notch_hz: u32) -> Self {
+        let cos = cos_q12(notch_hz as i64 * 0x10000 / sample_rate_hz.max(1) as i64);
+        let r = NOTCH_RADIUS;
+        let a1 = -2 * r * cos / Q12;
+        let a2 = r * r / Q12;
+        // Scale the zeros so the notch passes DC at unity gain
+        let den = (Q12 + a1 + a2).max(1);
+        let num = (2 * (Q12 - cos)).max(1);
+        let b0 = Q12 * den / num;
+        let b1 = -2 * cos * b0 / Q12;
+        // A notch close to DC needs huge zeros, which the sums saturate on
+        let coefficient = |c: i64| c.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+        Notch {
+            b0: coefficient(b0),
+            b1: coefficient(b1),
+            a1: a1 as i32,
+            a2: a2 as i32,
+            x: [0; 2],
+            y: [0; 2],
+            primed: false,
+        }
+    }
+
+    pub fn mains_flicker(sample_rate_hz: u32, mains_hz: u32) -> Self {
+        Notch::new(sample_rate_hz, 2 * mains_hz)
+    }
+}
+
+impl Filter for Notch {
+    fn process(&mut self, sample: LightIntensity) -> LightIntensity {
+        let x = sample as i32;
+        if !self.primed {
+            // Start settled on the first sample rather than ringing up from 0
+            self.x = [x; 2];
+            self.y = [x; 2];
+            self.primed = true;
+        }
+        // Typical notches stay well inside 32 bits, but saturate rather than
+        // wrap if one doesn't
+        let y = self
+            .b0
+            .saturating_mul(x + self.x[1])
+            .saturating_add(self.b1.saturating_mul(self.x[0]))
+            .saturating_sub(self.a1.saturating_mul(self.y[0]))
+            .saturating_sub(self.a2.saturating_mul(self.y[1]))
+            / Q12 as i32;
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        saturate(y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use heapless::consts::*;
+
+    #[test]
+    fn test_moving_average_and_median() {
+        let mut average: MovingAverage<U4> = MovingAverage::new().unwrap();
+        let averaged: std::vec::Vec<_> = [100, 100, 500, 100, 100, 100]
+            .iter()
+            .map(|x| average.process(*x))
+            .collect();
+        assert_eq!(std::vec![100, 100, 233, 200, 200, 200], averaged);
+
+        let mut median: Median<U3> = Median::new().unwrap();
+        let filtered: std::vec::Vec<_> = [100, 100, 900, 100, 900, 900, 100]
+            .iter()
+            .map(|x| median.process(*x))
+            .collect();
+        assert_eq!(std::vec![100, 100, 100, 100, 900, 900, 900], filtered);
+
+        assert!(MovingAverage::<U0>::new().is_none());
+        assert!(Median::<U0>::new().is_none());
+    }
+
+    #[test]
+    fn test_low_pass_and_dc_removal() {
+        let mut low_pass = LowPass::new(2).unwrap();
+        assert_eq!(0, low_pass.process(0));
+        let settled = (0..40).map(|_| low_pass.process(1000)).last();
+        assert_eq!(Some(999), settled);
+
+        let mut dc = DcRemoval::new(4, 500).unwrap();
+        let settled = (0..200).map(|_| dc.process(3000)).last();
+        assert_eq!(Some(500), settled);
+
+        // The widest shift leaves the output where it started
+        let mut still = LowPass::new(31).unwrap();
+        still.process(0);
+        assert_eq!(0, still.process(u16::MAX));
+        assert!(LowPass::new(32).is_none());
+        assert!(DcRemoval::new(255, 500).is_none());
+    }
+
+    #[test]
+    fn test_trig() {
+        for degrees in (0..360).step_by(15) {
+            let turns = degrees * 0x10000 / 360;
+            let expected = (degrees as f64).to_radians().cos() * Q12 as f64;
+            assert!((cos_q12(turns) as f64 - expected).abs() < 0.002 * Q12 as f64);
+        }
+    }
+
+    #[test]
+    fn test_notch_chain() {
+        // A lamp flickering at 100Hz on top of a keyed light, sampled at 1kHz
+        let keyed = |i: usize| {
+            if (i / 100) % 2 == 0 {
+                1000.0
+            } else {
+                3000.0
+            }
+        };
+        let flicker = |i: usize| 400.0 * (i as f64 * 0.1 * 2.0 * core::f64::consts::PI).sin();
+        let mut samples: std::vec::Vec<(Time, LightIntensity)> = (0..1000)
+            .map(|i| (i as Time, (keyed(i) + flicker(i)) as LightIntensity))
+            .collect();
+
+        let mut chain = Notch::mains_flicker(1000, 50).then(Median::<U3>::new().unwrap());
+        filter_samples(&mut samples, &mut chain);
+
+        // Away from the edges only a little ripple is left
+        for (i, (_, li)) in samples.iter().enumerate() {
+            if i % 100 > 30 {
+                assert!((*li as f64 - keyed(i)).abs() < 80.0, "{} at {}", li, i);
+            }
+        }
+    }
+}
@@ -0,0 +1,309 @@
+// The pipeline as lazy iterator adapters, so stages chain without a buffer
+// between them:
+//
+//     samples.light_events(cutoffs).symbols(unit).chars(CodeTable::standard())
+
+use crate::time::{TickCount, Timestamp};
+use crate::{
+    best_error_by, Absolute, CodeTable, LightIntensity, LightState, Morse, ScoreFn, Time,
+    TimedLightEvent, TimingModel,
+};
+use heapless::consts::U8;
+use heapless::Vec;
+
+pub trait SampleIterator<T: Timestamp>: Iterator<Item = (T, LightIntensity)> + Sized {
+    // Events between crossings of the (low, high) cutoffs, like `convert`
+    // starting dark at the first sample's time. The times can come straight
+    // from a wrapping counter.
+    fn light_events(self, cutoffs: (LightIntensity, LightIntensity)) -> LightEvents<Self, T> {
+        LightEvents {
+            samples: self,
+            cutoffs,
+            light_state: LightState::Dark,
+            last_edge: None,
+        }
+    }
+}
+
+impl<T: Timestamp, I: Iterator<Item = (T, LightIntensity)>> SampleIterator<T> for I {}
+
+pub struct LightEvents<I, T = Time> {
+    samples: I,
+    cutoffs: (LightIntensity, LightIntensity),
+    light_state: LightState,
+    last_edge: Option<T>,
+}
+
+impl<T: Timestamp, I: Iterator<Item = (T, LightIntensity)>> Iterator for LightEvents<I, T> {
+    type Item = TimedLightEvent<T::Duration>;
+
+    fn next(&mut self) -> Option<TimedLightEvent<T::Duration>> {
+        use LightState::*;
+        let (low_cut, high_cut) = self.cutoffs;
+        for (time, light) in &mut self.samples {
+            let start = *self.last_edge.get_or_insert(time);
+            let next_light_state = match (self.light_state, light) {
+                (Dark, x) if x > high_cut => Light,
+                (Light, x) if x < low_cut => Dark,
+                _ => continue,
+            };
+            let tle = TimedLightEvent {
+                light_state: self.light_state,
+                duration: time.since(start),
+            };
+            self.light_state = next_light_state;
+            self.last_edge = Some(time);
+            return Some(tle);
+        }
+        None
+    }
+}
+
+pub trait EventIterator<D: TickCount>: Iterator<Item = TimedLightEvent<D>> + Sized {
+    fn symbols(self, unit_millis: D) -> Symbols<'static, Self, Absolute, D> {
+        self.symbols_by(unit_millis, TimingModel::standard(), Absolute)
+    }
+
+    // Each event classified as whichever element of `model` fits it best, or
+    // `Morse::Error` if none could
+    fn symbols_by<S: ScoreFn>(
+        self,
+        unit_millis: D,
+        model: TimingModel,
+        scorer: S,
+    ) -> Symbols<Self, S, D> {
+        Symbols {
+            events: self,
+            unit_millis,
+            model,
+            scorer,
+        }
+    }
+}
+
+impl<D: TickCount, I: Iterator<Item = TimedLightEvent<D>>> EventIterator<D> for I {}
+
+pub struct Symbols<'a, I, S, D = Time> {
+    events: I,
+    unit_millis: D,
+    model: TimingModel<'a>,
+    scorer: S,
+}
+
+impl<'a, I, S, D> Iterator for Symbols<'a, I, S, D>
+where
+    I: Iterator<Item = TimedLightEvent<D>>,
+    S: ScoreFn,
+    D: TickCount,
+{
+    type Item = Morse;
+
+    fn next(&mut self) -> Option<Morse> {
+        let event = self.events.next()?;
+        Some(
+            match best_error_by(&event, self.unit_millis, &self.model, &self.scorer) {
+                Ok(best) => self.model.morse(best.item),
+                Err(_) => Morse::Error,
+            },
+        )
+    }
+}
+
+pub trait SymbolIterator: Iterator<Item = Morse> + Sized {
+    // Letters looked up in `table`, '?' for unknown codes, and a space between
+    // words, the same as `decode_events`
+    fn chars(self, table: CodeTable) -> Chars<Self> {
+        Chars {
+            symbols: self,
+            table,
+            code: Vec::new(),
+            started: false,
+            pending_space: false,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Morse>> SymbolIterator for I {}
+
+pub struct Chars<'a, I> {
+    symbols: I,
+    table: CodeTable<'a>,
+    // Longer than any known code, so an overflowing letter still decodes as
+    // unknown
+    code: Vec<Morse, U8>,
+    started: bool,
+    pending_space: bool,
+}
+
+impl<'a, I> Chars<'a, I> {
+    fn flush(&mut self) -> Option<char> {
+        if self.code.is_empty() {
+            return None;
+        }
+        let c = self.table.lookup(&self.code).unwrap_or('?');
+        while self.code.pop().is_some() {}
+        self.started = true;
+        Some(c)
+    }
+}
+
+impl<'a, I: Iterator<Item = Morse>> Iterator for Chars<'a, I> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        use Morse::*;
+        loop {
+            let symbol = match self.symbols.next() {
+                Some(symbol) => symbol,
+                // A final word space still ends in a space, as `decode_events`
+                // leaves one
+                None if self.code.is_empty() && self.pending_space => {
+                    self.pending_space = false;
+                    return Some(' ');
+                }
+                None => return self.flush(),
+            };
+            if self.pending_space && matches!(symbol, Dot | Dash | LongDash | Error) {
+                self.pending_space = false;
+                let _ = self.code.push(symbol);
+                return Some(' ');
+            }
+            match symbol {
+                TinySpace | InnerSpace => (),
+                LetterSpace => {
+                    if let Some(c) = self.flush() {
+                        return Some(c);
+                    }
+                }
+                WordSpace => {
+                    let c = self.flush();
+                    self.pending_space = self.started;
+                    if c.is_some() {
+                        return c;
+                    }
+                }
+                element => {
+                    let _ = self.code.push(element);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{convert, decode_events};
+    use heapless::consts::*;
+    use heapless::String;
+
+    #[test]
+    fn test_light_events_match_convert() {
+        let mut intensities: Vec<(Time, LightIntensity), U64> = Vec::new();
+        for (i, level) in [50, 50, 500, 520, 40, 60, 500, 30, 30, 510, 50]
+            .iter()
+            .enumerate()
+        {
+            intensities.push((i as Time * 5, *level)).unwrap();
+        }
+        let mut converted: Vec<TimedLightEvent, U16> = Vec::new();
+        convert(&intensities, &mut converted, 0).unwrap();
+        let cutoffs = crate::calc_digital_cutoffs(&intensities).unwrap();
+        let lazy: Vec<TimedLightEvent, U16> =
+            intensities.iter().copied().light_events(cutoffs).collect();
+        assert_eq!(converted, lazy);
+    }
+
+    #[test]
+    fn test_light_events_across_wrap() {
+        // A 16-bit millisecond counter rolling over mid-capture
+        let levels = [50, 500, 500, 50, 500, 50, 50];
+        let start = u16::MAX - 25;
+        let events: Vec<TimedLightEvent, U8> = levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| (start.wrapping_add(i as u16 * 10), *level))
+            .light_events((200, 300))
+            .collect();
+        let durations: Vec<Time, U8> = events.iter().map(|e| e.duration).collect();
+        assert_eq!(&[10, 20, 10, 10][..], &durations[..]);
+    }
+
+    #[test]
+    fn test_chain_to_chars() {
+        // "HI THERE" keyed at 10ms per sample step, straight from samples
+        // Alternating dark and light, in units
+        let units = [
+            5, 1, 1, 1, 1, 1, 1, 1, 3, 1, 1, 1, 7, 3, 3, 1, 1, 1, 1, 1, 1, 1, 3, 1, 3, 1, 1, 3, 1,
+            1, 3, 1, 5,
+        ];
+        let samples = units
+            .iter()
+            .enumerate()
+            .flat_map(|(i, n)| core::iter::repeat(i % 2).take(n * 10))
+            .enumerate()
+            .map(|(time, light)| (time as Time, 100 + 800 * light as LightIntensity));
+
+        let mut text: String<U16> = String::new();
+        for c in samples
+            .light_events((300, 700))
+            .skip(1)
+            .symbols(10)
+            .chars(CodeTable::standard())
+        {
+            text.push(c).unwrap();
+        }
+        assert_eq!("HI THERE", text.as_str());
+    }
+
+    #[test]
+    fn test_chars_match_decode_events() {
+        let durations = [
+            300, 100, 100, 700, 100, 100, 100, 300, 100, 100, 100, 700, 900, 100,
+        ];
+        let mut events: Vec<TimedLightEvent, U16> = Vec::new();
+        for (i, duration) in durations.iter().enumerate() {
+            let light_state = if i % 2 == 0 {
+                LightState::Light
+            } else {
+                LightState::Dark
+            };
+            events
+                .push(TimedLightEvent {
+                    light_state,
+                    duration: *duration,
+                })
+                .unwrap();
+        }
+        let mut decoded: String<U16> = String::new();
+        decode_events(&events, 100, &mut decoded).unwrap();
+        let mut lazy: String<U16> = String::new();
+        for c in events
+            .iter()
+            .copied()
+            .symbols(100)
+            .chars(CodeTable::standard())
+        {
+            lazy.push(c).unwrap();
+        }
+        assert_eq!(decoded, lazy);
+        assert_eq!("N II T", lazy.as_str());
+
+        // Ending on the word space after "II"
+        events.pop().unwrap();
+        events.pop().unwrap();
+        let mut decoded: String<U16> = String::new();
+        decode_events(&events, 100, &mut decoded).unwrap();
+        let mut lazy: String<U16> = String::new();
+        for c in events
+            .iter()
+            .copied()
+            .symbols(100)
+            .chars(CodeTable::standard())
+        {
+            lazy.push(c).unwrap();
+        }
+        assert_eq!(decoded, lazy);
+        assert_eq!("N II ", lazy.as_str());
+    }
+}
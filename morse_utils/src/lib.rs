@@ -1,5 +1,8 @@
 #![no_std]
+// Reading the code table from flash in `trie`
+#![cfg_attr(target_arch = "avr", feature(asm_experimental_arch))]
 
+#[allow(unused_macros)]
 macro_rules! hashmap {
     ($( $key: expr => $val: expr ),*) => {{
          let mut map = heapless::FnvIndexMap::new();
@@ -16,14 +19,30 @@ pub enum Morse {
     TinySpace,
     LetterSpace,
     WordSpace,
+    // American Morse only
+    LongDash,
+    InnerSpace,
 }
 
 extern crate heapless;
 
-use core::{cmp::min, convert::TryFrom};
-use heapless::consts::*;
-use heapless::FnvIndexMap;
-use heapless::Vec;
+pub mod channels;
+#[cfg(any(feature = "compact", test))]
+pub mod compact;
+pub mod correct;
+pub mod filter;
+pub mod iter;
+pub mod lm;
+pub mod notation;
+pub mod quality;
+pub mod segment;
+pub mod stream;
+pub mod time;
+pub mod trie;
+
+use heapless::consts::U8;
+use heapless::{String, Vec};
+use time::TickCount;
 
 pub type Time = i64;
 pub type LightIntensity = u16;
@@ -37,6 +56,12 @@ pub enum LightState {
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum MorseErr {
     TooFewTLEs,
+    TooManyTLEs,
+    EstimateMismatch,
+    OutputFull,
+    NoContrast,
+    // A channel index past the end of the samples
+    NoSuchChannel,
 }
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
@@ -45,10 +70,31 @@ pub struct Scored<T> {
     pub score: i64,
 }
 
+// The unit searches work in bare ticks and hand back the caller's type
+impl<D: TickCount> Scored<D> {
+    fn ticks(self) -> Scored<Time> {
+        Scored {
+            item: self.item.ticks(),
+            score: self.score,
+        }
+    }
+}
+
+impl Scored<Time> {
+    fn typed<D: TickCount>(self) -> Scored<D> {
+        Scored {
+            item: D::from_ticks(self.item),
+            score: self.score,
+        }
+    }
+}
+
+// `duration` is a bare `Time` in whatever ticks the capture used, unless it's
+// typed with its rate as in `time::Millis`
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
-pub struct TimedLightEvent {
+pub struct TimedLightEvent<D = Time> {
     pub light_state: LightState,
-    pub duration: Time,
+    pub duration: D,
 }
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
@@ -57,41 +103,239 @@ pub struct MorseCandidate {
     pub units: Time,
 }
 
-const MORSE_CANDIDATES: [MorseCandidate; 5] = [
-    MorseCandidate {
-        light_state: LightState::Light,
-        units: 1,
-    },
-    MorseCandidate {
-        light_state: LightState::Light,
-        units: 3,
-    },
-    MorseCandidate {
-        light_state: LightState::Dark,
-        units: 1,
-    },
-    MorseCandidate {
-        light_state: LightState::Dark,
-        units: 3,
-    },
-    MorseCandidate {
-        light_state: LightState::Dark,
-        units: 7,
-    },
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct TimingElement {
+    pub candidate: MorseCandidate,
+    pub morse: Morse,
+}
+
+impl TimingElement {
+    pub const fn new(light_state: LightState, units: Time, morse: Morse) -> Self {
+        TimingElement {
+            candidate: MorseCandidate { light_state, units },
+            morse,
+        }
+    }
+}
+
+// The candidate lengths every event gets scored against. `units` of each
+// candidate are counted in `1 / subdivisions` of a unit, so a 3.5 unit dash is
+// `units: 7` with `subdivisions: 2`. `weight`, in the same subdivisions, is
+// added to every light and taken from every dark to model a heavy or light
+// fist.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct TimingModel<'a> {
+    pub elements: &'a [TimingElement],
+    pub subdivisions: Time,
+    pub weight: Time,
+}
+
+const STANDARD_ELEMENTS: [TimingElement; 5] = [
+    TimingElement::new(LightState::Light, 1, Morse::Dot),
+    TimingElement::new(LightState::Light, 3, Morse::Dash),
+    TimingElement::new(LightState::Dark, 1, Morse::TinySpace),
+    TimingElement::new(LightState::Dark, 3, Morse::LetterSpace),
+    TimingElement::new(LightState::Dark, 7, Morse::WordSpace),
 ];
 
-pub fn calc_error(
-    event: &TimedLightEvent,
+// Railroad style American Morse: two unit dashes, a long dash for L, and the
+// wider spaces inside C, O, R, Y and Z
+const AMERICAN_ELEMENTS: [TimingElement; 7] = [
+    TimingElement::new(LightState::Light, 1, Morse::Dot),
+    TimingElement::new(LightState::Light, 2, Morse::Dash),
+    TimingElement::new(LightState::Light, 4, Morse::LongDash),
+    TimingElement::new(LightState::Dark, 1, Morse::TinySpace),
+    TimingElement::new(LightState::Dark, 2, Morse::InnerSpace),
+    TimingElement::new(LightState::Dark, 3, Morse::LetterSpace),
+    TimingElement::new(LightState::Dark, 6, Morse::WordSpace),
+];
+
+impl<'a> TimingModel<'a> {
+    pub const fn new(elements: &'a [TimingElement], subdivisions: Time) -> Self {
+        TimingModel {
+            elements,
+            subdivisions,
+            weight: 0,
+        }
+    }
+
+    pub const fn with_weight(self, weight: Time) -> Self {
+        TimingModel { weight, ..self }
+    }
+
+    // Candidate length in subdivisions after applying the keying weight
+    pub fn weighted_units(&self, candidate: &MorseCandidate) -> Time {
+        match candidate.light_state {
+            LightState::Light => candidate.units + self.weight,
+            LightState::Dark => candidate.units - self.weight,
+        }
+    }
+
+    pub fn expected_duration(&self, candidate: &MorseCandidate, unit_millis: Time) -> Time {
+        self.weighted_units(candidate) * unit_millis / self.subdivisions.max(1)
+    }
+
+    pub fn morse(&self, candidate: &MorseCandidate) -> Morse {
+        self.elements
+            .iter()
+            .find(|e| e.candidate == *candidate)
+            .map(|e| e.morse)
+            .unwrap_or(Morse::Error)
+    }
+}
+
+impl TimingModel<'static> {
+    pub const fn standard() -> Self {
+        TimingModel::new(&STANDARD_ELEMENTS, 1)
+    }
+
+    pub const fn american() -> Self {
+        TimingModel::new(&AMERICAN_ELEMENTS, 1)
+    }
+}
+
+impl Default for TimingModel<'static> {
+    fn default() -> Self {
+        TimingModel::standard()
+    }
+}
+
+pub trait ScoreFn {
+    // How badly an event of `actual` length fits a candidate of `expected`
+    // length, lower is better
+    fn score(&self, actual: Time, expected: Time) -> i64;
+}
+
+impl<F> ScoreFn for F
+where
+    F: Fn(Time, Time) -> i64,
+{
+    fn score(&self, actual: Time, expected: Time) -> i64 {
+        self(actual, expected)
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Absolute;
+
+impl ScoreFn for Absolute {
+    fn score(&self, actual: Time, expected: Time) -> i64 {
+        (actual - expected).abs()
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Squared;
+
+impl ScoreFn for Squared {
+    fn score(&self, actual: Time, expected: Time) -> i64 {
+        (actual - expected) * (actual - expected)
+    }
+}
+
+// Scores the log of the ratio in 1/1024ths of a doubling, so a 7 unit gap
+// that's 10% off costs the same as a 1 unit dot that's 10% off
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Relative;
+
+impl ScoreFn for Relative {
+    fn score(&self, actual: Time, expected: Time) -> i64 {
+        (log2_fixed(actual) - log2_fixed(expected)).abs()
+    }
+}
+
+// Squared error near the candidate and absolute error past `delta`, scaled so
+// the score is still in milliseconds
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Huber {
+    pub delta: Time,
+}
+
+impl ScoreFn for Huber {
+    fn score(&self, actual: Time, expected: Time) -> i64 {
+        let error = (actual - expected).abs();
+        let delta = self.delta.max(1);
+        if error <= delta {
+            error * error / (2 * delta)
+        } else {
+            error - delta / 2
+        }
+    }
+}
+
+// Absolute error, but no single event can contribute more than `cap`
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Capped {
+    pub cap: Time,
+}
+
+impl ScoreFn for Capped {
+    fn score(&self, actual: Time, expected: Time) -> i64 {
+        (actual - expected).abs().min(self.cap)
+    }
+}
+
+pub(crate) fn isqrt(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    // Not `div_ceil`, which is newer than the AVR toolchain
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+// log2(x) in 1/1024ths, treating anything below 1 as 1
+pub(crate) fn log2_fixed(x: Time) -> i64 {
+    let x = x.max(1) as u64;
+    let whole = 63 - x.leading_zeros();
+    // Mantissa in [1, 2) with 30 fractional bits
+    let mut mantissa = (x << (63 - whole)) >> 33;
+    let mut fraction = 0;
+    for bit in (0..10).rev() {
+        mantissa = (mantissa * mantissa) >> 30;
+        if mantissa >= 2 << 30 {
+            mantissa >>= 1;
+            fraction |= 1 << bit;
+        }
+    }
+    whole as i64 * 1024 + fraction
+}
+
+pub fn calc_error<D: TickCount>(
+    event: &TimedLightEvent<D>,
     candidate: &MorseCandidate,
-    unit_millis: Time,
+    unit_millis: D,
+) -> Option<i64> {
+    calc_error_by(
+        event,
+        candidate,
+        unit_millis,
+        &TimingModel::standard(),
+        &Absolute,
+    )
+}
+
+pub fn calc_error_by<S: ScoreFn, D: TickCount>(
+    event: &TimedLightEvent<D>,
+    candidate: &MorseCandidate,
+    unit_millis: D,
+    model: &TimingModel,
+    scorer: &S,
 ) -> Option<i64> {
     if event.light_state == candidate.light_state {
-        Some((event.duration - candidate.units * unit_millis).abs())
+        let expected = model.expected_duration(candidate, unit_millis.ticks());
+        Some(scorer.score(event.duration.ticks(), expected))
     } else {
         None
     }
 }
 
+#[allow(dead_code)]
 fn make_score(
     event: &TimedLightEvent,
     mc: &'static MorseCandidate,
@@ -121,13 +365,22 @@ fn poisoned_min<T>(
     })
 }
 
-pub fn best_error(
-    event: &TimedLightEvent,
-    unit_millis: Time,
-) -> Result<Scored<&MorseCandidate>, MorseErr> {
+pub fn best_error<D: TickCount>(
+    event: &TimedLightEvent<D>,
+    unit_millis: D,
+) -> Result<Scored<&'static MorseCandidate>, MorseErr> {
+    best_error_by(event, unit_millis, &TimingModel::standard(), &Absolute)
+}
+
+pub fn best_error_by<'a, S: ScoreFn, D: TickCount>(
+    event: &TimedLightEvent<D>,
+    unit_millis: D,
+    model: &TimingModel<'a>,
+    scorer: &S,
+) -> Result<Scored<&'a MorseCandidate>, MorseErr> {
     let mut best = None;
-    for mc in MORSE_CANDIDATES.iter() {
-        match (calc_error(event, mc, unit_millis), best) {
+    for mc in model.elements.iter().map(|e| &e.candidate) {
+        match (calc_error_by(event, mc, unit_millis, model, scorer), best) {
             (None, _) => continue,
             (Some(curr), None) => {
                 best = Some(Scored {
@@ -147,190 +400,1370 @@ pub fn best_error(
     best.ok_or(MorseErr::TooFewTLEs)
 }
 
-pub fn score_possible_unit_millis(
-    unit_millis: Time,
-    timings: &[TimedLightEvent],
-) -> Result<Scored<Time>, MorseErr> {
+// Most candidates either light state can have in a `Classifier`
+const CLASSIFIER_CANDIDATES: usize = 4;
+
+#[derive(Copy, Clone, Debug)]
+struct Boundaries<'a> {
+    candidates: [Option<&'a MorseCandidate>; CLASSIFIER_CANDIDATES],
+    expected: [Time; CLASSIFIER_CANDIDATES],
+    // Twice the midpoint between neighbouring candidates, less one where the
+    // longer would win a tie, so the candidate is the count of these below
+    // twice the duration
+    thresholds: [Time; CLASSIFIER_CANDIDATES - 1],
+}
+
+impl<'a> Boundaries<'a> {
+    fn new(
+        model: &TimingModel<'a>,
+        unit_millis: Time,
+        light_state: LightState,
+    ) -> Result<Self, MorseErr> {
+        // (expected, candidate) by expected length, keeping only the first of
+        // any that are the same length as `best_error` would
+        let mut sorted: Vec<(Time, usize), U8> = Vec::new();
+        for (i, element) in model.elements.iter().enumerate() {
+            let candidate = &element.candidate;
+            if candidate.light_state != light_state {
+                continue;
+            }
+            let expected = model.expected_duration(candidate, unit_millis);
+            if sorted.iter().any(|(e, _)| *e == expected) {
+                continue;
+            }
+            sorted
+                .push((expected, i))
+                .map_err(|_| MorseErr::OutputFull)?;
+            let mut at = sorted.len() - 1;
+            while at > 0 && sorted[at - 1].0 > expected {
+                sorted.swap(at - 1, at);
+                at -= 1;
+            }
+        }
+        if sorted.len() > CLASSIFIER_CANDIDATES {
+            return Err(MorseErr::OutputFull);
+        }
+
+        let mut boundaries = Boundaries {
+            candidates: [None; CLASSIFIER_CANDIDATES],
+            expected: [0; CLASSIFIER_CANDIDATES],
+            thresholds: [Time::MAX; CLASSIFIER_CANDIDATES - 1],
+        };
+        for (k, (expected, i)) in sorted.iter().enumerate() {
+            boundaries.candidates[k] = Some(&model.elements[*i].candidate);
+            boundaries.expected[k] = *expected;
+            if k > 0 {
+                let (shorter, earlier) = sorted[k - 1];
+                let longer_first = *i < earlier;
+                boundaries.thresholds[k - 1] = shorter + expected - longer_first as Time;
+            }
+        }
+        Ok(boundaries)
+    }
+}
+
+// `best_error` with the `Absolute` scorer for one unit time, worked out ahead
+// of time so each event takes a fixed few comparisons instead of a pass over
+// every candidate. Also matches `Squared`, which ranks candidates the same.
+#[derive(Copy, Clone, Debug)]
+pub struct Classifier<'a> {
+    light: Boundaries<'a>,
+    dark: Boundaries<'a>,
+}
+
+impl<'a> Classifier<'a> {
+    // Fails for models with more than 4 lights or 4 darks of different length
+    pub fn new<D: TickCount>(model: &TimingModel<'a>, unit_millis: D) -> Result<Self, MorseErr> {
+        Ok(Classifier {
+            light: Boundaries::new(model, unit_millis.ticks(), LightState::Light)?,
+            dark: Boundaries::new(model, unit_millis.ticks(), LightState::Dark)?,
+        })
+    }
+
+    pub fn classify<D: TickCount>(
+        &self,
+        event: &TimedLightEvent<D>,
+    ) -> Result<Scored<&'a MorseCandidate>, MorseErr> {
+        let boundaries = match event.light_state {
+            LightState::Light => &self.light,
+            LightState::Dark => &self.dark,
+        };
+        let twice = 2 * event.duration.ticks();
+        let k = boundaries
+            .thresholds
+            .iter()
+            .map(|t| (twice > *t) as usize)
+            .sum::<usize>();
+        let candidate = boundaries.candidates[k].ok_or(MorseErr::TooFewTLEs)?;
+        Ok(Scored {
+            item: candidate,
+            score: (event.duration.ticks() - boundaries.expected[k]).abs(),
+        })
+    }
+}
+
+pub fn score_possible_unit_millis<D: TickCount>(
+    unit_millis: D,
+    timings: &[TimedLightEvent<D>],
+) -> Result<Scored<D>, MorseErr> {
+    let classifier = Classifier::new(&TimingModel::standard(), unit_millis)?;
+    let mut sum = 0;
+    for event in timings {
+        sum += classifier.classify(event)?.score;
+    }
+    Ok(Scored {
+        item: unit_millis,
+        score: sum,
+    })
+}
+
+pub fn score_possible_unit_millis_by<S: ScoreFn, D: TickCount>(
+    unit_millis: D,
+    timings: &[TimedLightEvent<D>],
+    model: &TimingModel,
+    scorer: &S,
+) -> Result<Scored<D>, MorseErr> {
     let mut sum = 0;
 
     for event in timings {
-        let score = best_error(event, unit_millis)?.score;
+        let score = best_error_by(event, unit_millis, model, scorer)?.score;
         sum += score;
     }
 
-    let result = Ok(Scored {
+    Ok(Scored {
         item: unit_millis,
         score: sum,
-    });
+    })
+}
 
-    result
+pub fn estimate_unit_time<D: TickCount>(
+    timings: &[TimedLightEvent<D>],
+    min_millis: D,
+    max_millis: D,
+) -> Result<Scored<D>, MorseErr> {
+    (min_millis.ticks()..max_millis.ticks())
+        .map(|unit| score_possible_unit_millis(D::from_ticks(unit), timings))
+        .fold(None, poisoned_min)
+        .unwrap_or(Err(MorseErr::TooFewTLEs))
 }
 
-pub fn estimate_unit_time(
-    timings: &[TimedLightEvent],
-    min_millis: Time,
-    max_millis: Time,
-) -> Result<Scored<Time>, MorseErr> {
-    // Iterate over possible unit times from 1 to 5000 ms
-    (min_millis..max_millis)
+pub fn estimate_unit_time_by<S: ScoreFn, D: TickCount>(
+    timings: &[TimedLightEvent<D>],
+    min_millis: D,
+    max_millis: D,
+    model: &TimingModel,
+    scorer: &S,
+) -> Result<Scored<D>, MorseErr> {
+    (min_millis.ticks()..max_millis.ticks())
         // For each time, score it by summing the scores of the best candidate for each event
-        .map(|ratio| {
-            // let ratio = ratio as f32;
-            // let ratio = ratio / 100f32;
-            // let plus = (max_millis - min_millis) as f32 * ratio;
-            // let plus = plus as Time;
-            // score_possible_unit_millis(min_millis + plus, timings)
-            score_possible_unit_millis(ratio, timings)
-        })
+        .map(|unit| score_possible_unit_millis_by(D::from_ticks(unit), timings, model, scorer))
         // Converge on the minimum scoring unit time
         .fold(None, poisoned_min)
         // Ignore possible errors and pull out the best scoring unit time
         .unwrap_or(Err(MorseErr::TooFewTLEs))
 }
 
-fn fill_unit_time_possibilities() {
-    for i in 1..100 {
-        let i: f32 = i as f32;
+// How many events come before the first dark in events sorted lights first.
+// A binary search like `partition_point`, which the AVR toolchain predates.
+fn light_count<D>(sorted: &[TimedLightEvent<D>]) -> usize {
+    let (mut low, mut high) = (0, sorted.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if sorted[mid].light_state == LightState::Light {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
     }
+    low
 }
 
-pub fn calc_digital_cutoffs(
-    intensities: &[(Time, LightIntensity)],
-) -> Result<(LightIntensity, LightIntensity), core::num::TryFromIntError> {
-    let mut intensity_sum: u32 = 0; 
-
-    for (_, li) in intensities
-    {
-        intensity_sum += *li as u32;
-    }
-
-    let intensity_avg: u32 = intensity_sum / (intensities.len() as u32);
+// Runs a few rounds of 1-D k-means where the cluster centers are pinned to
+// multiples of the unit time: assign each event to its closest candidate, then
+// solve for the unit that minimizes the squared error of that assignment.
+fn converge_unit<S: ScoreFn, D: TickCount>(
+    timings: &[TimedLightEvent<D>],
+    seed: Time,
+    min_millis: Time,
+    max_millis: Time,
+    model: &TimingModel,
+    scorer: &S,
+) -> Time {
+    let clamp = |unit: Time| unit.max(min_millis).min(max_millis - 1);
+    let mut unit_millis = clamp(seed);
 
-        let mut lows = (0u32, 0u32);
-        let mut highs = (0u32, 0u32);
-    for (_ , li) in intensities
-    {
-        let li = *li as u32;
-        if li > intensity_avg
-        {
-           highs = (highs.0+1, highs.1 +li);
+    for _ in 0..16 {
+        let mut weighted_sum = 0;
+        let mut norm = 0;
+        for event in timings {
+            if let Ok(best) = best_error_by(event, D::from_ticks(unit_millis), model, scorer) {
+                let units = model.weighted_units(best.item);
+                weighted_sum += units * event.duration.ticks();
+                norm += units * units;
+            }
         }
-        else 
-        {
-           lows = (lows.0+1, lows.1 +li);
+        if norm == 0 {
+            break;
         }
 
+        // Candidate units are in subdivisions, so scale the fit back up
+        let weighted_sum = weighted_sum * model.subdivisions.max(1);
+        let next = clamp((weighted_sum + norm / 2) / norm);
+        if next == unit_millis {
+            break;
+        }
+        unit_millis = next;
     }
+    unit_millis
+}
 
-    let lows_avg = lows.1 / lows.0;
-    let highs_avg = highs.1 / highs.0;
-
-    let diff = highs_avg - lows_avg;
-    let low_cut = lows_avg + (diff / 4);
-    let high_cut = lows_avg + ((3 * diff) / 4);
+// The least squares center isn't necessarily where the summed absolute error
+// bottoms out, so walk downhill from it. The step doubles while the score keeps
+// improving and halves once it doesn't, so a long slope takes a logarithmic
+// number of scoring passes instead of one per millisecond, and the walk still
+// stops where neither neighbour scores better.
+fn descend_unit<S: ScoreFn, D: TickCount>(
+    timings: &[TimedLightEvent<D>],
+    unit_millis: Time,
+    min_millis: Time,
+    max_millis: Time,
+    model: &TimingModel,
+    scorer: &S,
+) -> Result<Scored<Time>, MorseErr> {
+    let score = |unit_millis| {
+        score_possible_unit_millis_by(D::from_ticks(unit_millis), timings, model, scorer)
+            .map(Scored::ticks)
+    };
+    let mut best = score(unit_millis)?;
+    let mut step: Time = 1;
+    loop {
+        let mut moved = false;
+        for next in [best.item - step, best.item + step].iter() {
+            if *next < min_millis || *next >= max_millis {
+                continue;
+            }
+            let scored = score(*next)?;
+            if scored.score < best.score {
+                best = scored;
+                moved = true;
+                break;
+            }
+        }
+        if moved {
+            step *= 2;
+        } else if step > 1 {
+            step /= 2;
+        } else {
+            return Ok(best);
+        }
+    }
+}
 
-    Ok((
-        low_cut as u16,
-        high_cut as u16,
-    ))
+fn local_minimum<S: ScoreFn, D: TickCount>(
+    timings: &[TimedLightEvent<D>],
+    seed: Time,
+    min_millis: Time,
+    max_millis: Time,
+    model: &TimingModel,
+    scorer: &S,
+) -> Result<Scored<Time>, MorseErr> {
+    let unit_millis = converge_unit(timings, seed, min_millis, max_millis, model, scorer);
+    descend_unit(timings, unit_millis, min_millis, max_millis, model, scorer)
 }
 
-pub fn convert<C>(
-    intensities: &[(Time, LightIntensity)],
-    light_states: &mut Vec<TimedLightEvent, C>,
-    start_time: Time,
-) -> Result<(), core::num::TryFromIntError>
+// Calls `found` with the local minimum reached from each seed. Different seeds
+// can land on different scales, e.g. a run of dots read as dashes at a third
+// of the unit time.
+fn clustered_local_minima<C, S, F, D>(
+    timings: &[TimedLightEvent<D>],
+    scratch: &mut Vec<TimedLightEvent<D>, C>,
+    min_millis: Time,
+    max_millis: Time,
+    model: &TimingModel,
+    scorer: &S,
+    mut found: F,
+) -> Result<(), MorseErr>
 where
-    C: heapless::ArrayLength<TimedLightEvent>,
+    C: heapless::ArrayLength<TimedLightEvent<D>>,
+    S: ScoreFn,
+    F: FnMut(&[TimedLightEvent<D>], Result<Scored<Time>, MorseErr>),
+    D: TickCount,
 {
-    use LightState::*;
-    let mut curr_light_state = Dark;
-    let mut start_time = start_time;
+    if min_millis >= max_millis {
+        return Err(MorseErr::TooFewTLEs);
+    }
 
-    let (low_cut, high_cut) = calc_digital_cutoffs(intensities)?;
+    // heapless' `clear` trips the slice bounds debug assertion, so pop instead
+    while scratch.pop().is_some() {}
+    scratch
+        .extend_from_slice(timings)
+        .map_err(|_| MorseErr::TooManyTLEs)?;
+    // Sort so the lights and darks each form a run ordered by duration
+    scratch.sort_unstable_by_key(|e| (e.light_state == LightState::Dark, e.duration.ticks()));
+    let split = light_count(scratch);
+    let (lights, darks) = scratch.split_at(split);
 
-    for (time, light) in intensities.iter() {
-        let next_light_state = match (curr_light_state, light) {
-            (Dark, x) if *x > high_cut => Some(Light),
-            (Light, x) if *x < low_cut => Some(Dark),
-            _ => None,
-        };
-        match next_light_state {
-            Some(next_light_state) => {
-                let tle = TimedLightEvent {
-                    light_state: curr_light_state,
-                    duration: *time - start_time,
-                };
-
-                light_states.push(tle);
-                curr_light_state = next_light_state;
-                start_time = *time;
+    for group in [lights, darks].iter() {
+        if let (Some(shortest), Some(median)) = (group.first(), group.get(group.len() / 2)) {
+            // The shortest event is usually one unit long, but a message of
+            // only dashes has no one unit lights, so also seed from the median
+            // read as both a one and a three unit event
+            let (shortest, median) = (shortest.duration.ticks(), median.duration.ticks());
+            for seed in [shortest, median, median / 3].iter() {
+                found(
+                    scratch,
+                    local_minimum(scratch, *seed, min_millis, max_millis, model, scorer),
+                );
             }
-            _ => (),
-        };
+        }
     }
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+pub fn estimate_unit_time_clustered<C, D>(
+    timings: &[TimedLightEvent<D>],
+    scratch: &mut Vec<TimedLightEvent<D>, C>,
+    min_millis: D,
+    max_millis: D,
+) -> Result<Scored<D>, MorseErr>
+where
+    C: heapless::ArrayLength<TimedLightEvent<D>>,
+    D: TickCount,
+{
+    estimate_unit_time_clustered_by(
+        timings,
+        scratch,
+        min_millis,
+        max_millis,
+        &TimingModel::standard(),
+        &Absolute,
+    )
+}
 
-    #[test]
-    fn test_calc_error_spoton() {
-        assert_eq!(
-            0,
-            calc_error(
-                &TimedLightEvent {
-                    light_state: LightState::Dark,
-                    duration: 600,
-                },
-                &MorseCandidate {
-                    light_state: LightState::Dark,
-                    units: 3,
-                },
-                200
-            )
-            .unwrap()
-        );
-    }
+pub fn estimate_unit_time_clustered_by<C, S, D>(
+    timings: &[TimedLightEvent<D>],
+    scratch: &mut Vec<TimedLightEvent<D>, C>,
+    min_millis: D,
+    max_millis: D,
+    model: &TimingModel,
+    scorer: &S,
+) -> Result<Scored<D>, MorseErr>
+where
+    C: heapless::ArrayLength<TimedLightEvent<D>>,
+    S: ScoreFn,
+    D: TickCount,
+{
+    let mut best = None;
+    clustered_local_minima(
+        timings,
+        scratch,
+        min_millis.ticks(),
+        max_millis.ticks(),
+        model,
+        scorer,
+        |_, next| {
+            best = poisoned_min(best, next);
+        },
+    )?;
+    best.unwrap_or(Err(MorseErr::TooFewTLEs)).map(Scored::typed)
+}
 
-    #[test]
-    fn test_calc_error_confused() {
-        assert_eq!(
-            200,
-            calc_error(
-                &TimedLightEvent {
-                    light_state: LightState::Light,
-                    duration: 300,
-                },
-                &MorseCandidate {
-                    light_state: LightState::Light,
-                    units: 1,
-                },
-                100
-            )
-            .unwrap()
-        );
+// How far apart, as a fraction of the unit, tied units from the clustered and
+// brute force searches can be
+const CHECK_TOLERANCE: Time = 10;
+
+pub fn estimate_unit_time_checked<C, D>(
+    timings: &[TimedLightEvent<D>],
+    scratch: &mut Vec<TimedLightEvent<D>, C>,
+    min_millis: D,
+    max_millis: D,
+) -> Result<Scored<D>, MorseErr>
+where
+    C: heapless::ArrayLength<TimedLightEvent<D>>,
+    D: TickCount,
+{
+    estimate_unit_time_checked_by(
+        timings,
+        scratch,
+        min_millis,
+        max_millis,
+        &TimingModel::standard(),
+        &Absolute,
+    )
+}
+
+pub fn estimate_unit_time_checked_by<C, S, D>(
+    timings: &[TimedLightEvent<D>],
+    scratch: &mut Vec<TimedLightEvent<D>, C>,
+    min_millis: D,
+    max_millis: D,
+    model: &TimingModel,
+    scorer: &S,
+) -> Result<Scored<D>, MorseErr>
+where
+    C: heapless::ArrayLength<TimedLightEvent<D>>,
+    S: ScoreFn,
+    D: TickCount,
+{
+    let clustered =
+        estimate_unit_time_clustered_by(timings, scratch, min_millis, max_millis, model, scorer)?;
+    let brute_force = estimate_unit_time_by(timings, min_millis, max_millis, model, scorer)?;
+
+    // Several unit times can tie, so the units only have to be close
+    let (unit, brute_force_unit) = (clustered.item.ticks(), brute_force.item.ticks());
+    let tolerance = brute_force_unit / CHECK_TOLERANCE + 1;
+    if clustered.score == brute_force.score && (unit - brute_force_unit).abs() <= tolerance {
+        Ok(clustered)
+    } else {
+        Err(MorseErr::EstimateMismatch)
     }
+}
 
-    fn best_error_helper(light_state: LightState, duration: i64, units: i64) -> i64 {
-        best_error(
-            &TimedLightEvent {
-                light_state,
-                duration,
-            },
-            units,
-        )
-        .unwrap()
-        .score
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum UnitHint {
+    Millis(Time, Time),
+    Wpm(Time, Time),
+}
+
+impl UnitHint {
+    // Half open range of unit times, like the `min_millis`/`max_millis`
+    // arguments taken by the estimators
+    pub fn millis_range(&self) -> (Time, Time) {
+        match *self {
+            UnitHint::Millis(min_millis, max_millis) => (min_millis, max_millis),
+            UnitHint::Wpm(min_wpm, max_wpm) => {
+                (wpm_to_unit_millis(max_wpm), wpm_to_unit_millis(min_wpm) + 1)
+            }
+        }
     }
+}
 
-    #[test]
-    fn test_best_error() {
-        use super::LightState::*;
+// "PARIS " is 50 units long, so one word per minute is 1200ms per unit
+pub fn wpm_to_unit_millis(wpm: Time) -> Time {
+    1200 / wpm.max(1)
+}
 
-        assert_eq!(100, best_error_helper(Dark, 200, 100));
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct UnitEstimate<D = Time> {
+    // Plausible unit times, best first. Scores are summed errors as returned
+    // by `score_possible_unit_millis`
+    pub candidates: Vec<Scored<D>, heapless::consts::U4>,
+    pub ambiguous: bool,
+}
+
+impl<D> UnitEstimate<D> {
+    pub fn best(&self) -> Option<&Scored<D>> {
+        self.candidates.first()
+    }
+}
+
+// Summed errors grow with the unit time, so compare candidates of different
+// scales by their average error per event in thousandths of a unit
+fn error_per_event(scored: &Scored<Time>, events: usize) -> i64 {
+    scored.score * 1000 / (scored.item.max(1) * events.max(1) as i64)
+}
+
+// A competing unit time is still plausible if it's within a tenth of a unit
+// per event of the best one
+const AMBIGUITY_MARGIN: i64 = 100;
+
+// `hint` is in the ticks of the events' durations
+pub fn estimate_unit_time_ambiguity<C, D>(
+    timings: &[TimedLightEvent<D>],
+    scratch: &mut Vec<TimedLightEvent<D>, C>,
+    hint: UnitHint,
+) -> Result<UnitEstimate<D>, MorseErr>
+where
+    C: heapless::ArrayLength<TimedLightEvent<D>>,
+    D: TickCount,
+{
+    let (min_millis, max_millis) = hint.millis_range();
+    let events = timings.len();
+    let mut minima: Vec<Scored<Time>, heapless::consts::U16> = Vec::new();
+    let add = |minima: &mut Vec<Scored<Time>, _>, next: Scored<Time>| {
+        // Treat unit times within 10% of each other as the same minimum
+        match minima
+            .iter_mut()
+            .find(|m| (m.item - next.item).abs() * 10 <= m.item.max(next.item))
+        {
+            Some(m) if error_per_event(&next, events) < error_per_event(m, events) => *m = next,
+            Some(_) => (),
+            None => {
+                let _ = minima.push(next);
+            }
+        }
+    };
+
+    let mut result = Ok(());
+    let model = TimingModel::standard();
+    let on_minimum = |sorted: &[TimedLightEvent<D>], next| {
+        let next = match (&result, next) {
+            (Ok(()), Ok(next)) => next,
+            (Ok(()), Err(e)) => {
+                result = Err(e);
+                return;
+            }
+            _ => return,
+        };
+        add(&mut minima, next);
+
+        // Whatever the seeds found, the same events read at three times or a
+        // third of the unit time are the usual competitors
+        for scale in [next.item * 3, next.item / 3].iter() {
+            if *scale < min_millis || *scale >= max_millis {
+                continue;
+            }
+            match local_minimum(sorted, *scale, min_millis, max_millis, &model, &Absolute) {
+                Ok(scaled) => add(&mut minima, scaled),
+                Err(e) => result = Err(e),
+            }
+        }
+    };
+    clustered_local_minima(
+        timings, scratch, min_millis, max_millis, &model, &Absolute, on_minimum,
+    )?;
+    result?;
+
+    minima.sort_unstable_by_key(|m| (error_per_event(m, events), m.item));
+    let best = *minima.first().ok_or(MorseErr::TooFewTLEs)?;
+    let cutoff = error_per_event(&best, events) + AMBIGUITY_MARGIN;
+
+    let mut candidates = Vec::new();
+    for m in minima
+        .iter()
+        .filter(|m| error_per_event(m, events) <= cutoff)
+    {
+        if candidates.push(m.typed()).is_err() {
+            break;
+        }
+    }
+
+    Ok(UnitEstimate {
+        ambiguous: candidates.len() > 1,
+        candidates,
+    })
+}
+
+// Fitted models count units in hundredths
+const FIT_SUBDIVISIONS: Time = 100;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct FittedTiming<D = Time> {
+    pub unit: Scored<D>,
+    // Dash length in hundredths of a unit, 300 for textbook timing
+    pub dash_ratio: Time,
+    // Hundredths of a unit added to each light and taken from each dark,
+    // positive for a heavy fist
+    pub weight: Time,
+    elements: [TimingElement; 5],
+}
+
+impl<D: TickCount> FittedTiming<D> {
+    pub fn model(&self) -> TimingModel<'_> {
+        TimingModel::new(&self.elements, FIT_SUBDIVISIONS).with_weight(self.weight)
+    }
+
+    fn new(unit_millis: Time, dash_ratio: Time, weight: Time) -> Self {
+        let mut elements = STANDARD_ELEMENTS;
+        for element in elements.iter_mut() {
+            element.candidate.units *= FIT_SUBDIVISIONS;
+            if element.morse == Morse::Dash {
+                element.candidate.units = dash_ratio;
+            }
+        }
+        FittedTiming {
+            unit: Scored {
+                item: D::from_ticks(unit_millis),
+                score: 0,
+            },
+            dash_ratio,
+            weight,
+            elements,
+        }
+    }
+}
+
+// Solves for the unit time and weight that best explain the dots and spaces,
+// whose lengths don't depend on the dash ratio. Each event is `units` long
+// plus the weight for lights or minus it for darks.
+fn fit_unit_and_weight<D: TickCount>(
+    timings: &[TimedLightEvent<D>],
+    fitted: &FittedTiming<D>,
+) -> Option<(Time, Time)> {
+    let model = fitted.model();
+    let (mut aa, mut ab, mut bb, mut ad, mut bd) = (0, 0, 0, 0, 0);
+    for event in timings {
+        let best = best_error_by(event, fitted.unit.item, &model, &Absolute).ok()?;
+        if model.morse(best.item) == Morse::Dash {
+            continue;
+        }
+        let a = best.item.units / FIT_SUBDIVISIONS;
+        let b = match event.light_state {
+            LightState::Light => 1,
+            LightState::Dark => -1,
+        };
+        aa += a * a;
+        ab += a * b;
+        bb += b * b;
+        ad += a * event.duration.ticks();
+        bd += b * event.duration.ticks();
+    }
+
+    let det = aa * bb - ab * ab;
+    if det == 0 {
+        // Every event looks the same, so the weight can't be told apart from
+        // the unit time
+        return None;
+    }
+    let unit_millis = (ad * bb - ab * bd) / det;
+    let weight_millis = (aa * bd - ab * ad) / det;
+    Some((unit_millis, weight_millis))
+}
+
+// Fits the dash ratio and keying weight along with the unit time, starting from
+// a unit time estimated with the standard model
+pub fn fit_timing<D>(
+    timings: &[TimedLightEvent<D>],
+    unit_millis: D,
+) -> Result<FittedTiming<D>, MorseErr>
+where
+    D: TickCount + PartialEq,
+{
+    let unit_millis = unit_millis.ticks();
+    let mut fitted = FittedTiming::new(unit_millis, 3 * FIT_SUBDIVISIONS, 0);
+
+    // Sloppy dashes can land nearer to the standard dot than the standard
+    // dash, so split the lights at the midpoint between the shortest and the
+    // longest for the first pass
+    let lights = timings
+        .iter()
+        .filter(|e| e.light_state == LightState::Light)
+        .map(|e| e.duration.ticks());
+    if let (Some(shortest), Some(longest)) = (lights.clone().min(), lights.max()) {
+        if longest >= 2 * shortest {
+            let split = (shortest + longest) / 2;
+            let dash_ratio = split * 2 * FIT_SUBDIVISIONS / unit_millis.max(1) - FIT_SUBDIVISIONS;
+            fitted = FittedTiming::new(unit_millis, dash_ratio, 0);
+        }
+    }
+
+    for _ in 0..4 {
+        let (unit_millis, weight_millis) = match fit_unit_and_weight(timings, &fitted) {
+            Some((unit_millis, weight_millis)) if unit_millis > 0 => (unit_millis, weight_millis),
+            _ => (fitted.unit.item.ticks(), 0),
+        };
+        // Keep a one unit space longer than nothing
+        let weight = (weight_millis * FIT_SUBDIVISIONS / unit_millis.max(1)).clamp(-45, 45);
+
+        let model = fitted.model();
+        let (mut dash_sum, mut dashes) = (0, 0);
+        for event in timings {
+            let best = best_error_by(event, fitted.unit.item, &model, &Absolute)?;
+            if model.morse(best.item) == Morse::Dash {
+                dash_sum += event.duration.ticks();
+                dashes += 1;
+            }
+        }
+        let dash_ratio = if dashes > 0 {
+            let dash_units = dash_sum * FIT_SUBDIVISIONS / (dashes * unit_millis.max(1));
+            // Dashes have to stay distinguishable from dots
+            (dash_units - weight).max(FIT_SUBDIVISIONS * 3 / 2)
+        } else {
+            fitted.dash_ratio
+        };
+
+        let next = FittedTiming::new(unit_millis, dash_ratio, weight);
+        if next == fitted {
+            break;
+        }
+        fitted = next;
+    }
+
+    fitted.unit =
+        score_possible_unit_millis_by(fitted.unit.item, timings, &fitted.model(), &Absolute)?;
+    Ok(fitted)
+}
+
+// How much longer lights measure than darks of the same nominal length, in
+// milliseconds. Slow sensor edges and the gap between the `convert` cutoffs
+// shift every transition by a fixed time regardless of the sending speed,
+// unlike the keying weight in `FittedTiming`, which scales with the unit.
+pub fn estimate_edge_bias<D: TickCount>(
+    timings: &[TimedLightEvent<D>],
+    unit_millis: D,
+) -> Option<Time> {
+    // Dots pair with intra-character gaps and dashes with letter gaps
+    let mut sums = [[(0, 0); 2]; 2];
+    for event in timings {
+        let best = best_error(event, unit_millis).ok()?;
+        let length = match best.item.units {
+            1 => 0,
+            3 => 1,
+            _ => continue,
+        };
+        let state = match event.light_state {
+            LightState::Light => 0,
+            LightState::Dark => 1,
+        };
+        let (sum, count) = &mut sums[length][state];
+        *sum += event.duration.ticks();
+        *count += 1;
+    }
+
+    let mut weighted_bias = 0;
+    let mut weight = 0;
+    for [(light_sum, lights), (dark_sum, darks)] in sums.iter() {
+        if *lights == 0 || *darks == 0 {
+            continue;
+        }
+        let pairs = *lights.min(darks);
+        weighted_bias += pairs * (light_sum / lights - dark_sum / darks) / 2;
+        weight += pairs;
+    }
+
+    if weight == 0 {
+        None
+    } else {
+        Some(weighted_bias / weight)
+    }
+}
+
+pub fn correct_edge_bias<D: TickCount>(timings: &mut [TimedLightEvent<D>], bias_millis: Time) {
+    for event in timings.iter_mut() {
+        let duration = event.duration.ticks();
+        event.duration = D::from_ticks(
+            match event.light_state {
+                LightState::Light => duration - bias_millis,
+                LightState::Dark => duration + bias_millis,
+            }
+            .max(0),
+        );
+    }
+}
+
+// Estimates the edge bias at `unit_millis` and removes it from `timings`,
+// returning the bias that was taken out
+pub fn compensate_edge_bias<D: TickCount>(
+    timings: &mut [TimedLightEvent<D>],
+    unit_millis: D,
+) -> Time {
+    let bias_millis = estimate_edge_bias(timings, unit_millis).unwrap_or(0);
+    correct_edge_bias(timings, bias_millis);
+    bias_millis
+}
+
+// The low and high cutoffs of `ThresholdMethod::Mean`, failing with
+// `NoContrast` rather than dividing by zero when there are no samples or they
+// never change
+pub fn calc_digital_cutoffs(
+    intensities: &[(Time, LightIntensity)],
+) -> Result<(LightIntensity, LightIntensity), MorseErr> {
+    calc_digital_cutoffs_by(intensities, ThresholdMethod::Mean).map(|cutoffs| cutoffs.pair())
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum ThresholdMethod {
+    // Split at the mean with cutoffs a quarter of the way in from each side,
+    // as `calc_digital_cutoffs` does
+    Mean,
+    // Split where the between class variance of the histogram peaks
+    Otsu,
+    // Split at the emptiest histogram bin between the two tallest peaks
+    Valley,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Cutoffs {
+    pub low: LightIntensity,
+    pub high: LightIntensity,
+    pub threshold: LightIntensity,
+    pub dark_mean: LightIntensity,
+    pub light_mean: LightIntensity,
+    pub dark_stddev: LightIntensity,
+    pub light_stddev: LightIntensity,
+    // Distance between the class means over their pooled standard deviation,
+    // in hundredths. Below a few hundred the classes overlap.
+    pub separation: i64,
+}
+
+impl Cutoffs {
+    pub fn pair(&self) -> (LightIntensity, LightIntensity) {
+        (self.low, self.high)
+    }
+}
+
+const HISTOGRAM_BINS: usize = 64;
+
+fn histogram(intensities: &[(Time, LightIntensity)]) -> Option<([u32; HISTOGRAM_BINS], u32, u32)> {
+    let lowest = intensities.iter().map(|(_, li)| *li as u32).min()?;
+    let highest = intensities.iter().map(|(_, li)| *li as u32).max()?;
+    let bin_width = (highest - lowest) / HISTOGRAM_BINS as u32 + 1;
+
+    let mut bins = [0u32; HISTOGRAM_BINS];
+    for (_, li) in intensities {
+        bins[((*li as u32 - lowest) / bin_width) as usize] += 1;
+    }
+    Some((bins, lowest, bin_width))
+}
+
+// Index of the last bin in the dark class
+fn otsu_split(bins: &[u32; HISTOGRAM_BINS]) -> usize {
+    let total: u64 = bins.iter().map(|c| *c as u64).sum();
+    let weighted_total: u64 = bins
+        .iter()
+        .enumerate()
+        .map(|(i, c)| i as u64 * *c as u64)
+        .sum();
+
+    let (mut dark, mut dark_weighted) = (0u64, 0u64);
+    let mut best = (0u64, 0);
+    for (i, count) in bins.iter().enumerate() {
+        dark += *count as u64;
+        dark_weighted += i as u64 * *count as u64;
+        let light = total - dark;
+        if dark == 0 || light == 0 {
+            continue;
+        }
+        // Between class variance, with the means in 1/1024ths of a bin
+        let mean_diff =
+            (weighted_total - dark_weighted) * 1024 / light - dark_weighted * 1024 / dark;
+        let variance = dark * mean_diff / total * light * mean_diff;
+        if variance > best.0 {
+            best = (variance, i);
+        }
+    }
+    best.1
+}
+
+fn valley_split(bins: &[u32; HISTOGRAM_BINS]) -> usize {
+    let smoothed =
+        |i: usize| bins[i.saturating_sub(1)] + bins[i] + bins[(i + 1).min(HISTOGRAM_BINS - 1)];
+
+    // The tallest peak, then the tallest one weighted by its distance from
+    // the first so a shoulder of the first peak doesn't win
+    let first = (0..HISTOGRAM_BINS)
+        .max_by_key(|i| smoothed(*i))
+        .unwrap_or(0);
+    let second = (0..HISTOGRAM_BINS)
+        .max_by_key(|i| smoothed(*i) as u64 * ((*i).max(first) - (*i).min(first)) as u64)
+        .unwrap_or(0);
+    let (left, right) = (first.min(second), first.max(second));
+
+    (left..right)
+        .min_by_key(|i| (smoothed(*i), *i))
+        .unwrap_or(left)
+}
+
+// Count, mean and standard deviation
+fn class_stats<F>(intensities: &[(Time, LightIntensity)], in_class: F) -> Option<(u64, u64, u64)>
+where
+    F: Fn(u64) -> bool,
+{
+    let (mut count, mut sum, mut sum_squares) = (0u64, 0u64, 0u64);
+    for (_, li) in intensities {
+        let li = *li as u64;
+        if in_class(li) {
+            count += 1;
+            sum += li;
+            sum_squares += li * li;
+        }
+    }
+    if count == 0 {
+        return None;
+    }
+    let mean = sum / count;
+    let variance = (sum_squares / count).saturating_sub(mean * mean);
+    Some((count, mean, isqrt(variance)))
+}
+
+pub fn calc_digital_cutoffs_by(
+    intensities: &[(Time, LightIntensity)],
+    method: ThresholdMethod,
+) -> Result<Cutoffs, MorseErr> {
+    let (bins, lowest, bin_width) = histogram(intensities).ok_or(MorseErr::NoContrast)?;
+    let threshold = match method {
+        ThresholdMethod::Mean => {
+            let sum: u64 = intensities.iter().map(|(_, li)| *li as u64).sum();
+            sum / intensities.len() as u64
+        }
+        ThresholdMethod::Otsu => (lowest + (otsu_split(&bins) as u32 + 1) * bin_width - 1) as u64,
+        ThresholdMethod::Valley => {
+            (lowest + (valley_split(&bins) as u32 + 1) * bin_width - 1) as u64
+        }
+    };
+
+    let (_, dark_mean, dark_stddev) =
+        class_stats(intensities, |li| li <= threshold).ok_or(MorseErr::NoContrast)?;
+    let (_, light_mean, light_stddev) =
+        class_stats(intensities, |li| li > threshold).ok_or(MorseErr::NoContrast)?;
+
+    let (low, high) = match method {
+        ThresholdMethod::Mean => {
+            let diff = light_mean - dark_mean;
+            (dark_mean + diff / 4, dark_mean + 3 * diff / 4)
+        }
+        _ => {
+            // Only turn on once clear of the dark noise and only turn off
+            // once clear of the light noise, but never closer to a class
+            // mean than halfway from the threshold
+            let high = (dark_mean + 3 * dark_stddev)
+                .max(threshold)
+                .min((threshold + light_mean) / 2);
+            let low = light_mean
+                .saturating_sub(3 * light_stddev)
+                .min(threshold)
+                .max((dark_mean + threshold) / 2);
+            (low, high)
+        }
+    };
+
+    let pooled = isqrt((dark_stddev * dark_stddev + light_stddev * light_stddev) / 2).max(1);
+    Ok(Cutoffs {
+        low: low as LightIntensity,
+        high: high as LightIntensity,
+        threshold: threshold as LightIntensity,
+        dark_mean: dark_mean as LightIntensity,
+        light_mean: light_mean as LightIntensity,
+        dark_stddev: dark_stddev as LightIntensity,
+        light_stddev: light_stddev as LightIntensity,
+        separation: ((light_mean - dark_mean) * 100 / pooled) as i64,
+    })
+}
+
+// `convert` with cutoffs from elsewhere, e.g. `calc_digital_cutoffs_by`.
+// Passing a resolution interpolates edges as `convert_interpolated` does.
+pub fn convert_with_cutoffs<C, D>(
+    intensities: &[(Time, LightIntensity)],
+    light_states: &mut Vec<TimedLightEvent<D>, C>,
+    start_time: Time,
+    cutoffs: (LightIntensity, LightIntensity),
+    resolution: Option<Time>,
+) -> Result<(), MorseErr>
+where
+    C: heapless::ArrayLength<TimedLightEvent<D>>,
+    D: TickCount,
+{
+    convert_inner(
+        intensities,
+        light_states,
+        start_time,
+        cutoffs,
+        resolution,
+        false,
+    )
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Polarity {
+    // A bright sample means the key is down
+    Normal,
+    // A dark sample means the key is down, e.g. a sensor behind a shutter
+    Inverted,
+    // Whichever of the two fits the timing model better
+    Auto,
+}
+
+// `convert_with_cutoffs` reading the light the way `polarity` says, returning
+// the polarity used. Auto converts both ways, using `scratch` to estimate a
+// unit time for each, and keeps the one with the lower error per event.
+pub fn convert_with_polarity<C, D>(
+    intensities: &[(Time, LightIntensity)],
+    light_states: &mut Vec<TimedLightEvent<D>, C>,
+    start_time: Time,
+    cutoffs: (LightIntensity, LightIntensity),
+    resolution: Option<Time>,
+    polarity: Polarity,
+    scratch: &mut Vec<TimedLightEvent<D>, C>,
+) -> Result<Polarity, MorseErr>
+where
+    C: heapless::ArrayLength<TimedLightEvent<D>>,
+    D: TickCount,
+{
+    let convert_as = |light_states: &mut Vec<TimedLightEvent<D>, C>, inverted| {
+        while light_states.pop().is_some() {}
+        convert_inner(
+            intensities,
+            light_states,
+            start_time,
+            cutoffs,
+            resolution,
+            inverted,
+        )
+    };
+    if polarity != Polarity::Auto {
+        convert_as(light_states, polarity == Polarity::Inverted)?;
+        return Ok(polarity);
+    }
+
+    let mut fit = |light_states: &mut Vec<TimedLightEvent<D>, C>, inverted| {
+        convert_as(light_states, inverted)?;
+        // The first event is however long the capture sat idle before the
+        // first edge. The relative error doesn't depend on the unit time, so
+        // the two readings compare fairly.
+        let events = light_states.get(1..).unwrap_or(&[]);
+        let longest = events.iter().map(|e| e.duration.ticks()).max().unwrap_or(0);
+        estimate_unit_time_clustered_by(
+            events,
+            scratch,
+            D::from_ticks(1),
+            D::from_ticks(longest + 1),
+            &TimingModel::standard(),
+            &Relative,
+        )
+        .map(|unit| unit.score / events.len() as i64)
+    };
+    let normal = fit(light_states, false);
+    let inverted = fit(light_states, true);
+    match (normal, inverted) {
+        (Err(e), Err(_)) => Err(e),
+        (Ok(normal), Ok(inverted)) if normal > inverted => Ok(Polarity::Inverted),
+        (Err(_), Ok(_)) => Ok(Polarity::Inverted),
+        _ => {
+            convert_as(light_states, false)?;
+            Ok(Polarity::Normal)
+        }
+    }
+}
+
+pub fn convert<C, D>(
+    intensities: &[(Time, LightIntensity)],
+    light_states: &mut Vec<TimedLightEvent<D>, C>,
+    start_time: Time,
+) -> Result<(), MorseErr>
+where
+    C: heapless::ArrayLength<TimedLightEvent<D>>,
+    D: TickCount,
+{
+    let cutoffs = calc_digital_cutoffs(intensities)?;
+    convert_inner(intensities, light_states, start_time, cutoffs, None, false)
+}
+
+// Like `convert`, but places each transition where the line between the two
+// samples around it crosses the cutoff instead of at the later sample. Times
+// in the resulting events are in `1 / resolution` of the input time steps, so
+// with millisecond timestamps and a resolution of 10 durations come out in
+// tenths of a millisecond.
+pub fn convert_interpolated<C, D>(
+    intensities: &[(Time, LightIntensity)],
+    light_states: &mut Vec<TimedLightEvent<D>, C>,
+    start_time: Time,
+    resolution: Time,
+) -> Result<(), MorseErr>
+where
+    C: heapless::ArrayLength<TimedLightEvent<D>>,
+    D: TickCount,
+{
+    let cutoffs = calc_digital_cutoffs(intensities)?;
+    convert_inner(
+        intensities,
+        light_states,
+        start_time,
+        cutoffs,
+        Some(resolution),
+        false,
+    )
+}
+
+fn convert_inner<C, D>(
+    intensities: &[(Time, LightIntensity)],
+    light_states: &mut Vec<TimedLightEvent<D>, C>,
+    start_time: Time,
+    (low_cut, high_cut): (LightIntensity, LightIntensity),
+    resolution: Option<Time>,
+    inverted: bool,
+) -> Result<(), MorseErr>
+where
+    C: heapless::ArrayLength<TimedLightEvent<D>>,
+    D: TickCount,
+{
+    use LightState::*;
+    let mut curr_light_state = Dark;
+    let mut start_time = start_time * resolution.unwrap_or(1);
+    let mut prev: Option<&(Time, LightIntensity)> = None;
+
+    for sample in intensities.iter() {
+        let (time, light) = sample;
+        let next_light_state = match (curr_light_state, light, inverted) {
+            (Dark, x, false) if *x > high_cut => Some((Light, high_cut)),
+            (Light, x, false) if *x < low_cut => Some((Dark, low_cut)),
+            (Dark, x, true) if *x < low_cut => Some((Light, low_cut)),
+            (Light, x, true) if *x > high_cut => Some((Dark, high_cut)),
+            _ => None,
+        };
+        if let Some((next_light_state, cut)) = next_light_state {
+            let edge_time = match (resolution, prev) {
+                (None, _) => *time,
+                (Some(resolution), None) => *time * resolution,
+                (Some(resolution), Some((prev_time, prev_light))) => {
+                    // The previous sample hadn't crossed `cut` yet, so this
+                    // lands between the two samples
+                    let rise = *light as Time - *prev_light as Time;
+                    let run = (*time - prev_time) * resolution;
+                    prev_time * resolution + (cut as Time - *prev_light as Time) * run / rise
+                }
+            };
+            let tle = TimedLightEvent {
+                light_state: curr_light_state,
+                duration: D::from_ticks(edge_time - start_time),
+            };
+
+            light_states.push(tle).map_err(|_| MorseErr::OutputFull)?;
+            curr_light_state = next_light_state;
+            start_time = edge_time;
+        }
+        prev = Some(sample);
+    }
+    Ok(())
+}
+
+const MORSE_CODES: [(&str, char); 54] = [
+    (".-", 'A'),
+    ("-...", 'B'),
+    ("-.-.", 'C'),
+    ("-..", 'D'),
+    (".", 'E'),
+    ("..-.", 'F'),
+    ("--.", 'G'),
+    ("....", 'H'),
+    ("..", 'I'),
+    (".---", 'J'),
+    ("-.-", 'K'),
+    (".-..", 'L'),
+    ("--", 'M'),
+    ("-.", 'N'),
+    ("---", 'O'),
+    (".--.", 'P'),
+    ("--.-", 'Q'),
+    (".-.", 'R'),
+    ("...", 'S'),
+    ("-", 'T'),
+    ("..-", 'U'),
+    ("...-", 'V'),
+    (".--", 'W'),
+    ("-..-", 'X'),
+    ("-.--", 'Y'),
+    ("--..", 'Z'),
+    ("-----", '0'),
+    (".----", '1'),
+    ("..---", '2'),
+    ("...--", '3'),
+    ("....-", '4'),
+    (".....", '5'),
+    ("-....", '6'),
+    ("--...", '7'),
+    ("---..", '8'),
+    ("----.", '9'),
+    (".-.-.-", '.'),
+    ("--..--", ','),
+    ("..--..", '?'),
+    (".----.", '\''),
+    ("-.-.--", '!'),
+    ("-..-.", '/'),
+    ("-.--.", '('),
+    ("-.--.-", ')'),
+    (".-...", '&'),
+    ("---...", ':'),
+    ("-.-.-.", ';'),
+    ("-...-", '='),
+    (".-.-.", '+'),
+    ("-....-", '-'),
+    ("..--.-", '_'),
+    (".-..-.", '"'),
+    ("...-..-", '$'),
+    (".--.-.", '@'),
+];
+
+// Letters by their codes in ".-" notation
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct CodeTable<'a> {
+    pub codes: &'a [(&'a str, char)],
+}
+
+impl<'a> CodeTable<'a> {
+    pub const fn new(codes: &'a [(&'a str, char)]) -> Self {
+        CodeTable { codes }
+    }
+
+    pub fn lookup(&self, code: &[Morse]) -> Option<char> {
+        self.codes
+            .iter()
+            .find(|(pattern, _)| {
+                pattern.len() == code.len()
+                    && pattern
+                        .bytes()
+                        .zip(code.iter())
+                        .all(|pair| matches!(pair, (b'.', Morse::Dot) | (b'-', Morse::Dash)))
+            })
+            .map(|(_, c)| *c)
+    }
+
+    // The ".-" code for a letter
+    pub fn code(&self, c: char) -> Option<&'a str> {
+        self.codes
+            .iter()
+            .find(|(_, letter)| *letter == c)
+            .map(|(pattern, _)| *pattern)
+    }
+}
+
+impl CodeTable<'static> {
+    pub const fn standard() -> Self {
+        CodeTable::new(&MORSE_CODES)
+    }
+}
+
+impl Default for CodeTable<'static> {
+    fn default() -> Self {
+        CodeTable::standard()
+    }
+}
+
+pub fn code_to_char(code: &[Morse]) -> Option<char> {
+    trie::lookup(code)
+}
+
+fn flush_code<C>(code: &mut Vec<Morse, U8>, text: &mut String<C>) -> Result<(), MorseErr>
+where
+    C: heapless::ArrayLength<u8>,
+{
+    if code.is_empty() {
+        return Ok(());
+    }
+    let c = code_to_char(code).unwrap_or('?');
+    while code.pop().is_some() {}
+    text.push(c).map_err(|_| MorseErr::OutputFull)
+}
+
+pub fn decode_events<C, D>(
+    events: &[TimedLightEvent<D>],
+    unit_millis: D,
+    text: &mut String<C>,
+) -> Result<(), MorseErr>
+where
+    C: heapless::ArrayLength<u8>,
+    D: TickCount,
+{
+    let model = TimingModel::standard();
+    let classifier = Classifier::new(&model, unit_millis)?;
+    let symbols = events.iter().map(|event| {
+        classifier
+            .classify(event)
+            .map(|best| model.morse(best.item))
+    });
+    decode_symbols(symbols, text)
+}
+
+// Appends the text spelled out by `events`, with '?' for any letter that isn't
+// a known code
+pub fn decode_events_by<C, S, D>(
+    events: &[TimedLightEvent<D>],
+    unit_millis: D,
+    model: &TimingModel,
+    scorer: &S,
+    text: &mut String<C>,
+) -> Result<(), MorseErr>
+where
+    C: heapless::ArrayLength<u8>,
+    S: ScoreFn,
+    D: TickCount,
+{
+    let symbols = events.iter().map(|event| {
+        best_error_by(event, unit_millis, model, scorer).map(|best| model.morse(best.item))
+    });
+    decode_symbols(symbols, text)
+}
+
+// Letters from classified events, stopping at the first event that couldn't
+// be classified
+pub(crate) fn decode_symbols<C, I>(symbols: I, text: &mut String<C>) -> Result<(), MorseErr>
+where
+    C: heapless::ArrayLength<u8>,
+    I: Iterator<Item = Result<Morse, MorseErr>>,
+{
+    use Morse::*;
+    // Longer than any known code, so an overflowing letter still decodes as
+    // unknown
+    let mut code: Vec<Morse, U8> = Vec::new();
+
+    for symbol in symbols {
+        match symbol? {
+            TinySpace | InnerSpace => (),
+            LetterSpace => flush_code(&mut code, text)?,
+            WordSpace => {
+                flush_code(&mut code, text)?;
+                if !text.is_empty() && !text.ends_with(' ') {
+                    text.push(' ').map_err(|_| MorseErr::OutputFull)?;
+                }
+            }
+            element => {
+                let _ = code.push(element);
+            }
+        }
+    }
+    flush_code(&mut code, text)
+}
+
+#[cfg(test)]
+#[allow(clippy::items_after_test_module)]
+mod tests {
+    use super::*;
+    use heapless::consts::*;
+
+    #[test]
+    fn test_calc_error_spoton() {
+        assert_eq!(
+            0,
+            calc_error(
+                &TimedLightEvent {
+                    light_state: LightState::Dark,
+                    duration: 600,
+                },
+                &MorseCandidate {
+                    light_state: LightState::Dark,
+                    units: 3,
+                },
+                200
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_calc_error_confused() {
+        assert_eq!(
+            200,
+            calc_error(
+                &TimedLightEvent {
+                    light_state: LightState::Light,
+                    duration: 300,
+                },
+                &MorseCandidate {
+                    light_state: LightState::Light,
+                    units: 1,
+                },
+                100
+            )
+            .unwrap()
+        );
+    }
+
+    fn best_error_helper(light_state: LightState, duration: i64, units: i64) -> i64 {
+        best_error(
+            &TimedLightEvent {
+                light_state,
+                duration,
+            },
+            units,
+        )
+        .unwrap()
+        .score
+    }
+
+    #[test]
+    fn test_best_error() {
+        use super::LightState::*;
+
+        assert_eq!(100, best_error_helper(Dark, 200, 100));
         assert_eq!(80, best_error_helper(Dark, 180, 100));
         assert_eq!(50, best_error_helper(Dark, 50, 100));
         assert_eq!(100, best_error_helper(Dark, 0, 100));
@@ -345,19 +1778,67 @@ mod tests {
         assert_eq!(0, best_error_helper(Light, 75, 25));
     }
 
+    #[test]
+    fn test_classifier_matches_best_error() {
+        use super::LightState::*;
+        // Longest first, so ties at the midpoints go the other way
+        let reversed = [
+            TimingElement::new(Light, 7, Morse::Dash),
+            TimingElement::new(Light, 2, Morse::Dot),
+            TimingElement::new(Dark, 14, Morse::WordSpace),
+            TimingElement::new(Dark, 6, Morse::LetterSpace),
+            TimingElement::new(Dark, 2, Morse::TinySpace),
+        ];
+        let models = [
+            TimingModel::standard(),
+            TimingModel::american(),
+            TimingModel::american().with_weight(1),
+            TimingModel::new(&reversed, 2),
+        ];
+        for model in models.iter() {
+            for &unit in [1, 3, 10, 100].iter() {
+                let classifier = Classifier::new(model, unit).unwrap();
+                for duration in 0..10 * unit {
+                    for &light_state in [Light, Dark].iter() {
+                        let event = TimedLightEvent {
+                            light_state,
+                            duration,
+                        };
+                        assert_eq!(
+                            best_error_by(&event, unit, model, &Absolute),
+                            classifier.classify(&event),
+                            "{:?} at unit {}",
+                            event,
+                            unit
+                        );
+                    }
+                }
+            }
+        }
+
+        let mut crowded = [TimingElement::new(Light, 1, Morse::Dot); 5];
+        for (i, element) in crowded.iter_mut().enumerate() {
+            *element = TimingElement::new(Light, i as Time + 1, Morse::Dot);
+        }
+        assert!(Classifier::new(&TimingModel::new(&crowded, 1), 10).is_err());
+        // Equal lengths count once, and darks with no candidate are an error
+        // like in `best_error`
+        let repeated = [TimingElement::new(Light, 1, Morse::Dot); 6];
+        let classifier = Classifier::new(&TimingModel::new(&repeated, 1), 10).unwrap();
+        let event = TimedLightEvent {
+            light_state: Dark,
+            duration: 10,
+        };
+        assert_eq!(Err(MorseErr::TooFewTLEs), classifier.classify(&event));
+    }
+
     fn helper_fill_events_slice<T>(durations: &[i64], vec: &mut Vec<TimedLightEvent, T>)
     where
         T: heapless::ArrayLength<TimedLightEvent>,
     {
-        for (i, duration) in durations.iter().enumerate() {
+        for duration in durations.iter() {
             vec.push(TimedLightEvent {
-                light_state: {
-                    if i % 2 == 0 {
-                        LightState::Dark
-                    } else {
-                        LightState::Dark
-                    }
-                },
+                light_state: LightState::Dark,
                 duration: *duration,
             })
             .unwrap();
@@ -382,33 +1863,631 @@ mod tests {
             estimate_unit_time(&timed_light_events, 0, 10000).unwrap()
         );
     }
+
+    #[test]
+    fn test_estimate_clustered() {
+        let test_durations = [
+            700, 300, 100, 100, 100, 100, 100, 100, 300, 300, 100, 300, 100, 300, 300, 100, 100,
+            100, 100, 300, 300, 300, 300, 300, 300, 100, 300, 300, 300, 100, 100, 700, 300, 100,
+            300, 100, 300, 300, 300, 100, 300, 100, 300, 300, 100, 100, 100, 100, 300, 100, 100,
+            700,
+        ];
+        let mut timed_light_events: Vec<TimedLightEvent, U128> = Vec::new();
+        helper_fill_events_slice(&test_durations, &mut timed_light_events);
+        let mut scratch: Vec<TimedLightEvent, U128> = Vec::new();
+        assert_eq!(
+            Scored {
+                item: 100,
+                score: 0
+            },
+            estimate_unit_time_clustered(&timed_light_events, &mut scratch, 0, 10000).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_estimate_clustered_slow_beacon() {
+        // A beacon keyed at half a minute per unit, where walking down the
+        // score one millisecond at a time would take thousands of passes
+        let mut timed_light_events: Vec<TimedLightEvent, U8> = Vec::new();
+        helper_fill_events_slice(
+            &[30_037, 89_880, 29_945, 210_300, 90_080, 30_012],
+            &mut timed_light_events,
+        );
+        let mut scratch: Vec<TimedLightEvent, U8> = Vec::new();
+        let estimate =
+            estimate_unit_time_checked(&timed_light_events, &mut scratch, 1, 60_000).unwrap();
+        assert!((29_900..=30_100).contains(&estimate.item));
+    }
+
+    #[test]
+    fn test_estimate_checked_noisy() {
+        use super::LightState::*;
+
+        // "PARIS" keyed at roughly 80ms with a sloppy hand
+        let events = [
+            (Dark, 612),
+            (Light, 77),
+            (Dark, 85),
+            (Light, 251),
+            (Dark, 74),
+            (Light, 236),
+            (Dark, 81),
+            (Light, 83),
+            (Dark, 247),
+            (Light, 79),
+            (Dark, 88),
+            (Light, 244),
+            (Dark, 239),
+            (Light, 86),
+            (Dark, 76),
+            (Light, 238),
+            (Dark, 82),
+            (Light, 74),
+            (Dark, 255),
+            (Light, 81),
+            (Dark, 79),
+            (Light, 84),
+            (Dark, 231),
+            (Light, 90),
+            (Dark, 77),
+            (Light, 78),
+            (Dark, 80),
+            (Light, 82),
+        ];
+        let mut timed_light_events: Vec<TimedLightEvent, U32> = Vec::new();
+        for (light_state, duration) in events.iter() {
+            timed_light_events
+                .push(TimedLightEvent {
+                    light_state: *light_state,
+                    duration: *duration,
+                })
+                .unwrap();
+        }
+        let mut scratch: Vec<TimedLightEvent, U32> = Vec::new();
+        let estimate =
+            estimate_unit_time_checked(&timed_light_events, &mut scratch, 1, 1000).unwrap();
+        assert!((78..=84).contains(&estimate.item));
+    }
+
+    #[test]
+    fn test_estimate_clustered_scratch_too_small() {
+        let mut timed_light_events: Vec<TimedLightEvent, U8> = Vec::new();
+        helper_fill_events_slice(&[100, 300, 100, 700], &mut timed_light_events);
+        let mut scratch: Vec<TimedLightEvent, U2> = Vec::new();
+        assert_eq!(
+            Err(MorseErr::TooManyTLEs),
+            estimate_unit_time_clustered(&timed_light_events, &mut scratch, 1, 1000)
+        );
+    }
+
+    pub(crate) fn helper_fill_alternating(durations: &[i64]) -> Vec<TimedLightEvent, U64> {
+        let mut vec = Vec::new();
+        for (i, duration) in durations.iter().enumerate() {
+            vec.push(TimedLightEvent {
+                light_state: if i % 2 == 0 {
+                    LightState::Light
+                } else {
+                    LightState::Dark
+                },
+                duration: *duration,
+            })
+            .unwrap();
+        }
+        vec
+    }
+
+    #[test]
+    fn test_estimate_ambiguous_scale() {
+        // "S" at 100ms looks exactly like "TTT" at 33ms
+        let timed_light_events = helper_fill_alternating(&[100, 100, 100, 100, 100]);
+        let mut scratch: Vec<TimedLightEvent, U64> = Vec::new();
+
+        let estimate = estimate_unit_time_ambiguity(
+            &timed_light_events,
+            &mut scratch,
+            UnitHint::Millis(1, 1000),
+        )
+        .unwrap();
+        assert!(estimate.ambiguous);
+        assert!(estimate.candidates.iter().any(|c| c.item == 100));
+        assert!(estimate.candidates.iter().any(|c| c.item == 33));
+
+        // 33ms is 36 WPM, well outside what this sender is expected to key
+        let estimate =
+            estimate_unit_time_ambiguity(&timed_light_events, &mut scratch, UnitHint::Wpm(5, 20))
+                .unwrap();
+        assert!(!estimate.ambiguous);
+        assert_eq!(100, estimate.best().unwrap().item);
+    }
+
+    #[test]
+    fn test_estimate_unambiguous() {
+        // "PARIS"
+        let timed_light_events = helper_fill_alternating(&[
+            100, 100, 300, 100, 300, 100, 100, 300, 100, 100, 300, 300, 100, 100, 300, 100, 100,
+            300, 100, 100, 100, 300, 100, 100, 100, 100, 100,
+        ]);
+        let mut scratch: Vec<TimedLightEvent, U64> = Vec::new();
+
+        let estimate = estimate_unit_time_ambiguity(
+            &timed_light_events,
+            &mut scratch,
+            UnitHint::Millis(1, 1000),
+        )
+        .unwrap();
+        assert!(!estimate.ambiguous);
+        assert_eq!(
+            &Scored {
+                item: 100,
+                score: 0
+            },
+            estimate.best().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_log2_fixed() {
+        assert_eq!(0, log2_fixed(1));
+        assert_eq!(1024, log2_fixed(2));
+        assert_eq!(10 * 1024, log2_fixed(1024));
+        assert_eq!(1623, log2_fixed(3));
+    }
+
+    #[test]
+    fn test_scorers() {
+        assert_eq!(20, Absolute.score(80, 100));
+        assert_eq!(400, Squared.score(80, 100));
+        assert_eq!(Relative.score(70, 100), Relative.score(490, 700));
+        assert_eq!(8, Huber { delta: 25 }.score(80, 100));
+        assert_eq!(88, Huber { delta: 25 }.score(200, 100));
+        assert_eq!(50, Capped { cap: 50 }.score(700, 100));
+    }
+
+    #[test]
+    fn test_estimate_outlier() {
+        use super::LightState::*;
+
+        // "ETE" after a long idle period, which drags the absolute error
+        // towards a unit where the idle time is a word space
+        let events = [
+            (Dark, 3000),
+            (Light, 100),
+            (Dark, 300),
+            (Light, 300),
+            (Dark, 300),
+            (Light, 100),
+        ];
+        let mut timed_light_events: Vec<TimedLightEvent, U8> = Vec::new();
+        for (light_state, duration) in events.iter() {
+            timed_light_events
+                .push(TimedLightEvent {
+                    light_state: *light_state,
+                    duration: *duration,
+                })
+                .unwrap();
+        }
+
+        assert_ne!(
+            100,
+            estimate_unit_time(&timed_light_events, 1, 1000)
+                .unwrap()
+                .item
+        );
+        assert_eq!(
+            100,
+            estimate_unit_time_by(
+                &timed_light_events,
+                1,
+                1000,
+                &TimingModel::standard(),
+                &Capped { cap: 200 }
+            )
+            .unwrap()
+            .item
+        );
+        assert_eq!(
+            100,
+            estimate_unit_time_by(
+                &timed_light_events,
+                1,
+                1000,
+                &TimingModel::standard(),
+                &Relative
+            )
+            .unwrap()
+            .item
+        );
+    }
+
+    #[test]
+    fn test_timing_model_dash_ratio() {
+        use super::LightState::*;
+        use super::Morse::*;
+
+        let elements = [
+            TimingElement::new(Light, 2, Dot),
+            TimingElement::new(Light, 7, Dash),
+            TimingElement::new(Dark, 2, TinySpace),
+            TimingElement::new(Dark, 6, LetterSpace),
+            TimingElement::new(Dark, 14, WordSpace),
+        ];
+        let model = TimingModel::new(&elements, 2);
+        let dash = TimedLightEvent {
+            light_state: Light,
+            duration: 350,
+        };
+
+        let best = best_error_by(&dash, 100, &model, &Absolute).unwrap();
+        assert_eq!(0, best.score);
+        assert_eq!(Dash, model.morse(best.item));
+        assert_eq!(50, best_error(&dash, 100).unwrap().score);
+    }
+
+    #[test]
+    fn test_timing_model_american() {
+        use super::LightState::*;
+
+        let model = TimingModel::american();
+        let morse_of = |light_state, duration| {
+            let event = TimedLightEvent {
+                light_state,
+                duration,
+            };
+            model.morse(best_error_by(&event, 100, &model, &Absolute).unwrap().item)
+        };
+
+        assert_eq!(Morse::Dash, morse_of(Light, 210));
+        assert_eq!(Morse::LongDash, morse_of(Light, 390));
+        assert_eq!(Morse::InnerSpace, morse_of(Dark, 190));
+        assert_eq!(Morse::WordSpace, morse_of(Dark, 640));
+        assert_eq!(
+            Morse::Error,
+            TimingModel::standard().morse(&MorseCandidate {
+                light_state: Light,
+                units: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn test_fit_timing_light_fist() {
+        use super::LightState::*;
+
+        // "MANTA" with 2.2 unit dashes and 35ms shaved off each light, so the
+        // dashes are closer to a textbook dot than a textbook dash
+        let timed_light_events = helper_fill_alternating(&[
+            185, 135, 185, 335, 65, 135, 185, 335, 185, 135, 65, 335, 185, 335, 65, 135, 185,
+        ]);
+        let dash = TimedLightEvent {
+            light_state: Light,
+            duration: 185,
+        };
+        assert_eq!(
+            Morse::Dot,
+            mc_to_morse(best_error(&dash, 100).unwrap().item)
+        );
+
+        let fitted = fit_timing(&timed_light_events, 100).unwrap();
+        assert_eq!(100, fitted.unit.item);
+        assert_eq!(220, fitted.dash_ratio);
+        assert_eq!(-35, fitted.weight);
+        assert_eq!(0, fitted.unit.score);
+
+        let model = fitted.model();
+        let best = best_error_by(&dash, fitted.unit.item, &model, &Absolute).unwrap();
+        assert_eq!(Morse::Dash, model.morse(best.item));
+    }
+
+    #[test]
+    fn test_fit_timing_textbook() {
+        // "PARIS"
+        let timed_light_events = helper_fill_alternating(&[
+            100, 100, 300, 100, 300, 100, 100, 300, 100, 100, 300, 300, 100, 100, 300, 100, 100,
+            300, 100, 100, 100, 300, 100, 100, 100, 100, 100,
+        ]);
+        let fitted = fit_timing(&timed_light_events, 97).unwrap();
+        assert_eq!(100, fitted.unit.item);
+        assert_eq!(300, fitted.dash_ratio);
+        assert_eq!(0, fitted.weight);
+
+        // A zero starting unit still fits rather than dividing by zero
+        assert!(fit_timing(&timed_light_events, 0).is_ok());
+        assert!(fit_timing(&[], 0).is_ok());
+    }
+
+    #[test]
+    fn test_edge_bias() {
+        // "PARIS" from a sensor that rises fast and falls slowly
+        let paris = [
+            100, 100, 300, 100, 300, 100, 100, 300, 100, 100, 300, 300, 100, 100, 300, 100, 100,
+            300, 100, 100, 100, 300, 100, 100, 100, 100, 100,
+        ];
+        let mut timed_light_events = helper_fill_alternating(&paris);
+        correct_edge_bias(&mut timed_light_events, -24);
+        assert_eq!(124, timed_light_events[0].duration);
+        assert_eq!(76, timed_light_events[1].duration);
+
+        let unit = estimate_unit_time(&timed_light_events, 1, 1000).unwrap();
+        assert_eq!(Some(24), estimate_edge_bias(&timed_light_events, unit.item));
+        assert_eq!(24, compensate_edge_bias(&mut timed_light_events, unit.item));
+        assert_eq!(helper_fill_alternating(&paris), timed_light_events);
+    }
+
+    #[test]
+    fn test_convert_interpolated() {
+        let intensities = [
+            (0, 0),
+            (10, 0),
+            (20, 900),
+            (30, 1000),
+            (40, 1000),
+            (50, 100),
+            (60, 0),
+            (70, 0),
+        ];
+
+        let mut quantized: Vec<TimedLightEvent, U8> = Vec::new();
+        convert(&intensities, &mut quantized, 0).unwrap();
+        assert_eq!(20, quantized[0].duration);
+        assert_eq!(30, quantized[1].duration);
+
+        // Cutoffs are 256 and 729
+        let mut interpolated: Vec<TimedLightEvent, U8> = Vec::new();
+        convert_interpolated(&intensities, &mut interpolated, 0, 10).unwrap();
+        assert_eq!(
+            &[
+                TimedLightEvent {
+                    light_state: LightState::Dark,
+                    duration: 181,
+                },
+                TimedLightEvent {
+                    light_state: LightState::Light,
+                    duration: 301,
+                },
+            ],
+            &interpolated[..]
+        );
+
+        // Events past the end of the output are an error, not dropped
+        let mut short: Vec<TimedLightEvent, U1> = Vec::new();
+        assert_eq!(
+            Err(MorseErr::OutputFull),
+            convert_interpolated(&intensities, &mut short, 0, 10)
+        );
+    }
+
+    #[test]
+    fn test_convert_interpolated_30fps() {
+        // "PARIS" at 100ms per unit, filmed at 30fps. Each frame's brightness
+        // is how much of the frame the lamp was lit for.
+        let paris = [
+            100, 100, 300, 100, 300, 100, 100, 300, 100, 100, 300, 300, 100, 100, 300, 100, 100,
+            300, 100, 100, 100, 300, 100, 100, 100, 100, 100,
+        ];
+        let mut lit: Vec<(Time, Time), U32> = Vec::new();
+        let mut time = 500;
+        for (i, duration) in paris.iter().enumerate() {
+            if i % 2 == 0 {
+                lit.push((time, time + duration)).unwrap();
+            }
+            time += duration;
+        }
+
+        let mut intensities: Vec<(Time, LightIntensity), U256> = Vec::new();
+        for frame in 0..(time + 500) / 33 {
+            let (start, end) = (frame * 33, frame * 33 + 33);
+            let overlap: Time = lit
+                .iter()
+                .map(|(on, off)| (end.min(*off) - start.max(*on)).max(0))
+                .sum();
+            intensities
+                .push((start + 16, (50 + 900 * overlap / 33) as LightIntensity))
+                .unwrap();
+        }
+
+        let mut quantized: Vec<TimedLightEvent, U64> = Vec::new();
+        convert(&intensities, &mut quantized, 0).unwrap();
+        let mut interpolated: Vec<TimedLightEvent, U64> = Vec::new();
+        convert_interpolated(&intensities, &mut interpolated, 0, 10).unwrap();
+
+        // Skip the leading idle period
+        let worst_error = |events: &[TimedLightEvent], scale: Time| {
+            events[1..]
+                .iter()
+                .zip(paris.iter())
+                .map(|(event, expected)| (event.duration - expected * scale).abs())
+                .max()
+                .unwrap()
+        };
+        assert!(worst_error(&quantized, 1) >= 30);
+        assert!(worst_error(&interpolated, 10) <= 90);
+
+        let unit = estimate_unit_time(&interpolated[1..], 500, 1500).unwrap();
+        assert!((980..=1020).contains(&unit.item));
+    }
+
+    #[test]
+    fn test_decode_events() {
+        // "PARIS PARIS" after an idle period, with an unknown letter on the end
+        let paris = [
+            100, 100, 300, 100, 300, 100, 100, 300, 100, 100, 300, 300, 100, 100, 300, 100, 100,
+            300, 100, 100, 100, 300, 100, 100, 100, 100, 100,
+        ];
+        let mut timed_light_events: Vec<TimedLightEvent, U128> = Vec::new();
+        let mut push = |light_state, duration| {
+            timed_light_events
+                .push(TimedLightEvent {
+                    light_state,
+                    duration,
+                })
+                .unwrap()
+        };
+        push(LightState::Dark, 2000);
+        for _ in 0..2 {
+            for (i, duration) in paris.iter().enumerate() {
+                if i % 2 == 0 {
+                    push(LightState::Light, *duration);
+                } else {
+                    push(LightState::Dark, *duration);
+                }
+            }
+            push(LightState::Dark, 700);
+        }
+        for _ in 0..9 {
+            push(LightState::Light, 100);
+            push(LightState::Dark, 100);
+        }
+
+        let mut text: String<U32> = String::new();
+        decode_events(&timed_light_events, 100, &mut text).unwrap();
+        assert_eq!("PARIS PARIS ?", text.as_str());
+
+        let mut short: String<U4> = String::new();
+        assert_eq!(
+            Err(MorseErr::OutputFull),
+            decode_events(&timed_light_events, 100, &mut short)
+        );
+    }
+
+    #[test]
+    fn test_code_to_char() {
+        use super::Morse::*;
+
+        assert_eq!(Some('E'), code_to_char(&[Dot]));
+        assert_eq!(Some('Q'), code_to_char(&[Dash, Dash, Dot, Dash]));
+        assert_eq!(Some('0'), code_to_char(&[Dash, Dash, Dash, Dash, Dash]));
+        assert_eq!(None, code_to_char(&[Dot, Dot, Dot, Dot, Dot, Dot, Dot]));
+        assert_eq!(None, code_to_char(&[]));
+    }
+
+    fn helper_lopsided_intensities() -> Vec<(Time, LightIntensity), U256> {
+        // A long noisy idle period followed by a short burst of keying
+        let mut intensities = Vec::new();
+        for i in 0..200 {
+            let noise = [0, 40, 15, 70, 25, 55, 5, 60][i % 8];
+            intensities.push((i as Time * 5, 100 + noise)).unwrap();
+        }
+        for i in 200..240 {
+            let level = if (i / 4) % 2 == 0 { 900 } else { 130 };
+            intensities
+                .push((i as Time * 5, level - (i % 3) as LightIntensity * 10))
+                .unwrap();
+        }
+        intensities
+    }
+
+    #[test]
+    fn test_cutoffs_otsu_lopsided() {
+        let intensities = helper_lopsided_intensities();
+        for method in [ThresholdMethod::Otsu, ThresholdMethod::Valley].iter() {
+            let cutoffs = calc_digital_cutoffs_by(&intensities, *method).unwrap();
+            // Clear of the idle noise, and below the dimmest light
+            assert!(cutoffs.high > 170);
+            assert!(cutoffs.low < 880);
+            assert!(cutoffs.low <= cutoffs.threshold && cutoffs.threshold <= cutoffs.high);
+            assert!(cutoffs.separation > 1000);
+        }
+
+        let mean = calc_digital_cutoffs_by(&intensities, ThresholdMethod::Mean).unwrap();
+        let (low, high) = calc_digital_cutoffs(&intensities).unwrap();
+        assert_eq!((low, high), mean.pair());
+    }
+
+    #[test]
+    fn test_cutoffs_without_contrast() {
+        let flat = [(0, 300), (5, 300), (10, 300)];
+        assert_eq!(Err(MorseErr::NoContrast), calc_digital_cutoffs(&flat));
+        assert_eq!(Err(MorseErr::NoContrast), calc_digital_cutoffs(&[]));
+
+        let mut events: Vec<TimedLightEvent, U8> = Vec::new();
+        assert_eq!(Err(MorseErr::NoContrast), convert(&flat, &mut events, 0));
+        assert_eq!(
+            Err(MorseErr::NoContrast),
+            convert_interpolated(&[], &mut events, 0, 10)
+        );
+    }
+
+    #[test]
+    fn test_cutoffs_separation() {
+        let clean = helper_lopsided_intensities();
+        let mut murky: Vec<(Time, LightIntensity), U256> = Vec::new();
+        for (time, li) in clean.iter() {
+            murky.push((*time, 300 + li / 4)).unwrap();
+        }
+        let clean = calc_digital_cutoffs_by(&clean, ThresholdMethod::Otsu).unwrap();
+        let murky = calc_digital_cutoffs_by(&murky, ThresholdMethod::Otsu).unwrap();
+        assert!(murky.separation < clean.separation);
+
+        assert_eq!(
+            Err(MorseErr::NoContrast),
+            calc_digital_cutoffs_by(&[(0, 500), (5, 500)], ThresholdMethod::Otsu)
+        );
+        assert_eq!(
+            Err(MorseErr::NoContrast),
+            calc_digital_cutoffs_by(&[], ThresholdMethod::Valley)
+        );
+    }
+
+    #[test]
+    fn test_convert_auto_polarity() {
+        // "SOS" keyed by darkening a lit sensor, then the same lit normally
+        let sos = [1, 1, 1, 1, 1, 3, 3, 1, 3, 1, 3, 3, 1, 1, 1, 1, 1];
+        let mut shuttered: Vec<(Time, LightIntensity), U512> = Vec::new();
+        let mut lit: Vec<(Time, LightIntensity), U512> = Vec::new();
+        let mut time = 0;
+        for (i, units) in [5].iter().chain(sos.iter()).chain([5].iter()).enumerate() {
+            for _ in 0..units * 10 {
+                let keyed = i % 2 == 1;
+                shuttered
+                    .push((time, if keyed { 80 } else { 700 }))
+                    .unwrap();
+                lit.push((time, if keyed { 700 } else { 80 })).unwrap();
+                time += 1;
+            }
+        }
+
+        let mut events: Vec<TimedLightEvent, U64> = Vec::new();
+        let mut scratch = Vec::new();
+        for (samples, expected) in
+            [(&shuttered, Polarity::Inverted), (&lit, Polarity::Normal)].iter()
+        {
+            let cutoffs = calc_digital_cutoffs_by(samples, ThresholdMethod::Otsu).unwrap();
+            let polarity = convert_with_polarity(
+                samples,
+                &mut events,
+                0,
+                cutoffs.pair(),
+                None,
+                Polarity::Auto,
+                &mut scratch,
+            )
+            .unwrap();
+            assert_eq!(*expected, polarity);
+
+            let mut text: String<U8> = String::new();
+            decode_events(&events[1..], 10, &mut text).unwrap();
+            assert_eq!("SOS", text.as_str());
+        }
+
+        // Forcing the wrong way round swaps elements and gaps
+        let cutoffs = calc_digital_cutoffs_by(&lit, ThresholdMethod::Otsu).unwrap();
+        convert_with_polarity(
+            &lit,
+            &mut events,
+            0,
+            cutoffs.pair(),
+            None,
+            Polarity::Inverted,
+            &mut scratch,
+        )
+        .unwrap();
+        assert_eq!(LightState::Light, events[1].light_state);
+        assert_eq!(50, events[1].duration);
+    }
 }
 
 pub fn mc_to_morse(mc: &MorseCandidate) -> Morse {
-    use Morse::*;
-    match mc {
-        MorseCandidate {
-            light_state: LightState::Light,
-            units: 1,
-        } => Dot,
-        MorseCandidate {
-            light_state: LightState::Light,
-            units: 3,
-        } => Dash,
-        MorseCandidate {
-            light_state: LightState::Dark,
-            units: 1,
-        } => TinySpace,
-        MorseCandidate {
-            light_state: LightState::Dark,
-            units: 3,
-        } => LetterSpace,
-        MorseCandidate {
-            light_state: LightState::Dark,
-            units: 7,
-        } => WordSpace,
-        _ => Morse::Error,
-    }
+    TimingModel::standard().morse(mc)
 }
 
 // fn char_to_morse(c: char) -> Morse {
@@ -0,0 +1,411 @@
+// Rescoring decoded words against a vocabulary and character bigrams, so a
+// word like "HELIO" that decoded cleanly but isn't a word can become the
+// "HELLO" that's a couple of elements away. Changing a letter costs the number
+// of elements it takes to turn one code into the other, less however much the
+// timing already doubted it. A word outside every vocabulary costs a little
+// per letter, plus more the less its letter pairs look like the vocabulary's.
+
+use crate::correct::Correction;
+use crate::{log2_fixed, CodeTable, MorseErr, Time};
+use heapless::consts::U16;
+use heapless::{ArrayLength, String, Vec};
+
+// Costs are in hundredths of a Morse element
+const ELEMENT_COST: i64 = 100;
+// No change is free, however doubtful the letter
+const MIN_CHANGE: i64 = 10;
+const OUT_OF_VOCABULARY: i64 = 60;
+// Per bit of surprise for each letter pair
+const BIGRAM_COST: i64 = 10;
+// Longest word that's rescored; longer ones are passed through
+const MAX_WORD: usize = 16;
+// A word can have one letter changed for every this many, and always one,
+// so a short unknown word isn't swapped for an unrelated short word
+const LETTERS_PER_CHANGE: usize = 3;
+
+// Letters and digits, then the word boundary
+const SYMBOLS: usize = 37;
+const BOUNDARY: usize = 36;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Vocabulary<'a> {
+    pub words: &'a [&'a str],
+}
+
+impl<'a> Vocabulary<'a> {
+    pub const fn new(words: &'a [&'a str]) -> Self {
+        Vocabulary { words }
+    }
+
+    pub fn contains(&self, word: &str) -> bool {
+        self.words.iter().any(|w| w.eq_ignore_ascii_case(word))
+    }
+}
+
+const ENGLISH_WORDS: [&str; 120] = [
+    "A", "ABOUT", "AFTER", "ALL", "ALSO", "AN", "AND", "ANY", "ARE", "AS", "AT", "BACK", "BE",
+    "BECAUSE", "BUT", "BY", "CAN", "COME", "COULD", "DAY", "DO", "EVEN", "FIRST", "FOR", "FROM",
+    "GET", "GIVE", "GO", "GOOD", "HAVE", "HE", "HELLO", "HELP", "HER", "HERE", "HIM", "HIS", "HOW",
+    "I", "IF", "IN", "INTO", "IS", "IT", "ITS", "JUST", "KNOW", "LIKE", "LOOK", "MAKE", "ME",
+    "MORE", "MOST", "MY", "NEW", "NO", "NOT", "NOW", "OF", "ON", "ONE", "ONLY", "OR", "OTHER",
+    "OUR", "OUT", "OVER", "PARIS", "PEOPLE", "QUICK", "SAY", "SEE", "SEND", "SHE", "SO", "SOME",
+    "SOS", "TAKE", "TEST", "THAN", "THAT", "THE", "THEIR", "THEM", "THEN", "THERE", "THESE",
+    "THEY", "THINK", "THIS", "TIME", "TO", "TWO", "UP", "US", "USE", "WANT", "WAY", "WE", "WELL",
+    "WHAT", "WHEN", "WHICH", "WHO", "WILL", "WITH", "WORK", "WORLD", "WOULD", "YEAR", "YES", "YOU",
+    "YOUR", "BROWN", "FOX", "JUMPS", "LAZY", "DOG", "MESSAGE", "RECEIVED",
+];
+
+// Q-codes, prosigns and the usual contest and ragchew abbreviations
+const HAM_WORDS: [&str; 77] = [
+    "QRL", "QRM", "QRN", "QRO", "QRP", "QRQ", "QRS", "QRT", "QRU", "QRV", "QRX", "QRZ", "QSB",
+    "QSL", "QSO", "QSY", "QTH", "CQ", "DE", "K", "KN", "SK", "AR", "BK", "BT", "TNX", "TKS", "TU",
+    "FB", "OM", "YL", "XYL", "RST", "UR", "ES", "HR", "HW", "WX", "ANT", "RIG", "PWR", "73", "88",
+    "GM", "GA", "GE", "GN", "DX", "NR", "FER", "PSE", "AGN", "CUL", "SRI", "ABT", "599", "5NN",
+    "TEST", "NAME", "OP", "CFM", "INFO", "VY", "GL", "BCNU", "HI", "R", "RPT", "SIG", "WPM", "CW",
+    "POTA", "SOTA", "EE", "TT", "DIT", "DAH",
+];
+
+pub const ENGLISH: Vocabulary<'static> = Vocabulary::new(&ENGLISH_WORDS);
+pub const HAM: Vocabulary<'static> = Vocabulary::new(&HAM_WORDS);
+
+fn symbol(c: u8) -> Option<usize> {
+    match c.to_ascii_uppercase() {
+        c @ b'A'..=b'Z' => Some((c - b'A') as usize),
+        c @ b'0'..=b'9' => Some((c - b'0') as usize + 26),
+        _ => None,
+    }
+}
+
+// Something like W1AW or 9A2XY: a short prefix with a letter in it, a digit,
+// then a suffix of letters
+pub fn is_callsign(word: &str) -> bool {
+    let bytes = word.as_bytes();
+    let digit = match bytes.iter().rposition(|b| b.is_ascii_digit()) {
+        Some(digit) => digit,
+        None => return false,
+    };
+    let (prefix, suffix) = (&bytes[..digit], &bytes[digit + 1..]);
+    (1..=3).contains(&prefix.len())
+        && prefix.iter().all(|b| b.is_ascii_alphanumeric())
+        && prefix.iter().any(|b| b.is_ascii_alphabetic())
+        && (1..=4).contains(&suffix.len())
+        && suffix.iter().all(|b| b.is_ascii_alphabetic())
+}
+
+// Elements to add, remove or flip to turn one letter's code into another's
+fn code_distance(table: &CodeTable, a: u8, b: u8) -> Option<i64> {
+    let code = |c: u8| {
+        table
+            .codes
+            .iter()
+            .find(|(_, letter)| *letter == c.to_ascii_uppercase() as char)
+            .map(|(code, _)| code.as_bytes())
+    };
+    let (a, b) = (code(a)?, code(b)?);
+    let mut previous = [0i64; 9];
+    let mut current = [0i64; 9];
+    if b.len() >= previous.len() {
+        return None;
+    }
+    for (j, d) in previous.iter_mut().enumerate() {
+        *d = j as i64;
+    }
+    for (i, x) in a.iter().enumerate() {
+        current[0] = i as i64 + 1;
+        for (j, y) in b.iter().enumerate() {
+            current[j + 1] = (previous[j] + (x != y) as i64)
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        previous = current;
+    }
+    Some(previous[b.len()])
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Reason {
+    // The new word is in a vocabulary
+    Dictionary,
+    // Neither is, but the new word's letter pairs are much more likely
+    Letters,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Change {
+    // Which word of the text, counting from 0
+    pub word: usize,
+    pub from: String<U16>,
+    pub to: String<U16>,
+    pub reason: Reason,
+    pub cost: i64,
+}
+
+pub struct LanguageModel<'a> {
+    vocabularies: &'a [Vocabulary<'a>],
+    table: CodeTable<'a>,
+    // How often each symbol follows each other, from the vocabularies
+    bigrams: [[u16; SYMBOLS]; SYMBOLS],
+    totals: [u32; SYMBOLS],
+}
+
+impl<'a> LanguageModel<'a> {
+    pub fn new(vocabularies: &'a [Vocabulary<'a>], table: CodeTable<'a>) -> Self {
+        let mut lm = LanguageModel {
+            vocabularies,
+            table,
+            bigrams: [[0; SYMBOLS]; SYMBOLS],
+            totals: [0; SYMBOLS],
+        };
+        for vocabulary in vocabularies {
+            for word in vocabulary.words {
+                let mut previous = BOUNDARY;
+                let symbols = word.bytes().filter_map(symbol).chain(Some(BOUNDARY));
+                for next in symbols {
+                    let count = &mut lm.bigrams[previous][next];
+                    *count = count.saturating_add(1);
+                    lm.totals[previous] += 1;
+                    previous = next;
+                }
+            }
+        }
+        lm
+    }
+
+    pub fn in_vocabulary(&self, word: &str) -> bool {
+        is_callsign(word) || self.vocabularies.iter().any(|v| v.contains(word))
+    }
+
+    // Bits of surprise over the word's letter pairs, as a cost
+    fn bigram_cost(&self, word: &[u8]) -> i64 {
+        let mut cost = 0;
+        let mut previous = BOUNDARY;
+        let symbols = word.iter().map(|c| symbol(*c).unwrap_or(BOUNDARY));
+        for next in symbols.chain(Some(BOUNDARY)) {
+            let seen = self.bigrams[previous][next] as Time + 1;
+            let total = self.totals[previous] as Time + SYMBOLS as Time;
+            cost += (log2_fixed(total) - log2_fixed(seen)) * BIGRAM_COST / 1024;
+            previous = next;
+        }
+        cost
+    }
+
+    // Callsigns aren't counted, so noise never gets "corrected" into one
+    fn word_cost(&self, word: &[u8]) -> i64 {
+        match core::str::from_utf8(word) {
+            Ok(w) if self.vocabularies.iter().any(|v| v.contains(w)) => 0,
+            _ => OUT_OF_VOCABULARY * word.len() as i64 + self.bigram_cost(word),
+        }
+    }
+
+    // What it costs to read letter `from` as `to`, given how much `doubt`
+    // the timing already had about it
+    fn change_cost(&self, from: u8, to: u8, doubt: i64) -> Option<i64> {
+        if from.eq_ignore_ascii_case(&to) {
+            return Some(0);
+        }
+        let distance = code_distance(&self.table, from, to)?;
+        Some((distance * ELEMENT_COST - doubt).max(MIN_CHANGE))
+    }
+
+    // The cheapest reading of one word, if it's better than the word as sent
+    fn rescore_word(&self, word: &[u8], doubts: &[i64]) -> Option<(Vec<u8, U16>, Reason, i64)> {
+        if matches!(core::str::from_utf8(word), Ok(w) if is_callsign(w)) {
+            return None;
+        }
+        let keep = self.word_cost(word);
+        let max_changed = (word.len() / LETTERS_PER_CHANGE).max(1);
+        let mut best: Option<(Vec<u8, U16>, Reason, i64)> = None;
+        let mut consider = |candidate: &[u8], reason: Reason, change: i64| {
+            let total = change + self.word_cost(candidate);
+            let beaten = best.as_ref().map_or(keep, |b| b.2);
+            if total < beaten {
+                best = Some((candidate.iter().copied().collect(), reason, total));
+            }
+        };
+
+        // Vocabulary words the same length
+        for vocabulary in self.vocabularies {
+            for v in vocabulary.words.iter().map(|v| v.as_bytes()) {
+                let changed = word
+                    .iter()
+                    .zip(v.iter())
+                    .filter(|(a, b)| !a.eq_ignore_ascii_case(b))
+                    .count();
+                if v.len() != word.len() || changed == 0 || changed > max_changed {
+                    continue;
+                }
+                let change = word
+                    .iter()
+                    .zip(v.iter())
+                    .zip(doubts.iter())
+                    .map(|((from, to), doubt)| self.change_cost(*from, *to, *doubt))
+                    .try_fold(0, |sum, cost| Some(sum + cost?));
+                if let Some(change) = change {
+                    consider(v, Reason::Dictionary, change);
+                }
+            }
+        }
+
+        // Any single letter swapped for one a dot or dash away
+        let mut candidate: Vec<u8, U16> = word.iter().copied().collect();
+        for i in 0..word.len() {
+            for (_, c) in self.table.codes.iter() {
+                let c = *c as u8;
+                if symbol(c).is_none() || code_distance(&self.table, word[i], c) != Some(1) {
+                    continue;
+                }
+                candidate[i] = c;
+                let change = self.change_cost(word[i], c, doubts[i]).unwrap_or(0);
+                let reason = match self.word_cost(&candidate) {
+                    0 => Reason::Dictionary,
+                    _ => Reason::Letters,
+                };
+                consider(&candidate, reason, change);
+            }
+            candidate[i] = word[i];
+        }
+        best
+    }
+
+    // Rewrites the decoded `letters` into `text`, replacing words that are
+    // cheaper to read as something else. `unit_millis` scales the letters'
+    // correction costs into doubt about them.
+    pub fn rescore<C, N>(
+        &self,
+        letters: &[Correction],
+        unit_millis: Time,
+        text: &mut String<C>,
+        changes: &mut Vec<Change, N>,
+    ) -> Result<(), MorseErr>
+    where
+        C: ArrayLength<u8>,
+        N: ArrayLength<Change>,
+    {
+        let unit = unit_millis.max(1);
+        for (i, word) in letters.split(|l| l.c == ' ').enumerate() {
+            if i > 0 {
+                text.push(' ').map_err(|_| MorseErr::OutputFull)?;
+            }
+            let mut original: Vec<u8, U16> = Vec::new();
+            let mut doubts: Vec<i64, U16> = Vec::new();
+            // Words too long to hold, or with non-ASCII letters, are left as
+            // they are
+            let rescored = if word.len() <= MAX_WORD && word.iter().all(|l| l.c.is_ascii()) {
+                for letter in word {
+                    let _ = original.push(letter.c as u8);
+                    let _ = doubts.push(letter.cost * ELEMENT_COST / unit);
+                }
+                self.rescore_word(&original, &doubts)
+            } else {
+                None
+            };
+            match rescored {
+                Some((to, reason, cost)) => {
+                    for c in to.iter() {
+                        text.push(*c as char).map_err(|_| MorseErr::OutputFull)?;
+                    }
+                    let mut change = Change {
+                        word: i,
+                        from: String::new(),
+                        to: String::new(),
+                        reason,
+                        cost,
+                    };
+                    for (from, to) in original.iter().zip(to.iter()) {
+                        let _ = change.from.push(*from as char);
+                        let _ = change.to.push(*to as char);
+                    }
+                    changes.push(change).map_err(|_| MorseErr::OutputFull)?;
+                }
+                None => {
+                    for letter in word {
+                        text.push(letter.c).map_err(|_| MorseErr::OutputFull)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::consts::*;
+
+    fn helper_letters(text: &str, doubtful: usize, cost: i64) -> Vec<Correction, U64> {
+        text.chars()
+            .enumerate()
+            .map(|(i, c)| Correction {
+                c,
+                cost: if i == doubtful { cost } else { 0 },
+            })
+            .collect()
+    }
+
+    fn helper_rescore(
+        lm: &LanguageModel,
+        letters: &[Correction],
+    ) -> (String<U64>, Vec<Change, U8>) {
+        let mut text = String::new();
+        let mut changes = Vec::new();
+        lm.rescore(letters, 100, &mut text, &mut changes).unwrap();
+        (text, changes)
+    }
+
+    #[test]
+    fn test_dictionary_rescoring() {
+        let vocabularies = [ENGLISH, HAM];
+        let lm = LanguageModel::new(&vocabularies, CodeTable::standard());
+        let (text, changes) = helper_rescore(&lm, &helper_letters("HELIO WORLD", 99, 0));
+        assert_eq!("HELLO WORLD", text.as_str());
+        assert_eq!(1, changes.len());
+        assert_eq!(
+            ("HELIO", "HELLO", Reason::Dictionary, 0),
+            (
+                changes[0].from.as_str(),
+                changes[0].to.as_str(),
+                changes[0].reason,
+                changes[0].word
+            )
+        );
+
+        // Callsigns and words already in a vocabulary are left alone
+        let (text, changes) = helper_rescore(&lm, &helper_letters("CQ CQ DE W1AW K", 99, 0));
+        assert_eq!("CQ CQ DE W1AW K", text.as_str());
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn test_doubt_lowers_change_cost() {
+        // QTH with its H read as an S, which is cheaper to fix the less sure
+        // the timing was of it
+        let lm = LanguageModel::new(&[HAM], CodeTable::standard());
+        let sure = lm.change_cost(b'S', b'H', 0).unwrap();
+        let unsure = lm.change_cost(b'S', b'H', 50).unwrap();
+        assert_eq!(ELEMENT_COST - 50, unsure);
+        assert!(unsure < sure);
+        let (text, changes) = helper_rescore(&lm, &helper_letters("UR QTS", 5, 80));
+        assert_eq!("UR QTH", text.as_str());
+        assert_eq!(1, changes[0].word);
+    }
+
+    #[test]
+    fn test_vocabulary_choice() {
+        // Without the ham words, a Q-code looks like noise and an unknown
+        // word far from anything is kept
+        let lm = LanguageModel::new(&[ENGLISH], CodeTable::standard());
+        assert!(!lm.in_vocabulary("QRZ"));
+        assert!(lm.in_vocabulary("9A2XY"));
+        assert!(!is_callsign("599"));
+        let (text, changes) = helper_rescore(&lm, &helper_letters("XQZJ", 99, 0));
+        assert_eq!("XQZJ", text.as_str());
+        assert!(changes.is_empty());
+
+        let custom = ["MORSE", "CODE"];
+        let vocabularies = [ENGLISH, Vocabulary::new(&custom)];
+        let lm = LanguageModel::new(&vocabularies, CodeTable::standard());
+        let (text, _) = helper_rescore(&lm, &helper_letters("MORSE CODF", 99, 0));
+        assert_eq!("MORSE CODE", text.as_str());
+    }
+}
@@ -1,9 +1,19 @@
-
 use heapless::consts::*;
 use heapless::Vec;
 
+use morse_utils::correct::{decode_corrected, Correction};
+use morse_utils::iter::{EventIterator, SymbolIterator};
+use morse_utils::lm::{Change, LanguageModel, Vocabulary, ENGLISH, HAM};
+use morse_utils::notation::{encode_text, time_symbols, Notation, Sequence};
+use morse_utils::quality::{analyze, detect_morse, Detection, SignalReport, DEFAULT_SQUELCH};
+use morse_utils::segment::{decode_transmission, split_transmissions, SplitConfig, Transmission};
 use morse_utils::*;
 
+use std::env;
+use std::fs;
+use std::process;
+
+#[allow(unused_macros)]
 macro_rules! hashmap {
     ($( $key: expr => $val: expr ),*) => {{
          let mut map = ::std::collections::HashMap::new();
@@ -35,11 +45,13 @@ macro_rules! hashmap {
 
 // }
 
+#[allow(non_upper_case_globals)]
 const test_durations : [i64; 52] = [
         700, 300, 100, 100, 100, 100, 100, 100, 300, 300, 100, 300, 100, 300, 300, 100, 100, 100,
         100, 300, 300, 300, 300, 300, 300, 100, 300, 300, 300, 100, 100, 700, 300, 100, 300, 100,
         300, 300, 300, 100, 300, 100, 300, 300, 100, 100, 100, 100, 300, 100, 100, 700,
     ];
+#[allow(non_upper_case_globals)]
  const myint: [(Time, LightIntensity); 9] = [
         (5, 50),
         (10, 50),
@@ -53,6 +65,7 @@ const test_durations : [i64; 52] = [
     ];
 
 
+#[allow(clippy::if_same_then_else)]
 fn helper_fill_events_slice<T>(durations: &[i64], vec: &mut Vec<TimedLightEvent, T>)
 where
     T: heapless::ArrayLength<TimedLightEvent>,
@@ -72,45 +85,565 @@ where
     }
 }
 
+// Captures are split where a level is held for this long
+const DEFAULT_IDLE_GAP: Time = 1000;
 
+// Unit for `timing` when none is given
+const DEFAULT_UNIT: Time = 100;
 
+// Video edges are placed to a tenth of a millisecond
+const VIDEO_RESOLUTION: Time = 10;
+// Brightness is rescaled onto this range before thresholding
+const VIDEO_FULL_SCALE: f64 = 4095.0;
 
-fn main() -> () {
-    use morse_utils::*;
+fn usage() -> ! {
+    eprintln!(
+        "usage: morse_utils [video <brightness.csv> [--seconds] [--report] [--squelch <percent>]]"
+    );
+    eprintln!("       morse_utils split <capture.txt> [--idle <samples>] [--squelch <percent>]");
+    eprintln!("                         [--polarity normal|inverted|auto]");
+    eprintln!("                         [--lm [--vocab <words.txt>]]");
+    eprintln!("       morse_utils encode <text>");
+    eprintln!("       morse_utils decode <notation>");
+    eprintln!("       morse_utils timing <notation> [--unit <ms>]");
+    eprintln!("       morse_utils notation <timings.txt> [--unit <ms>]");
+    process::exit(1);
+}
 
-let mut timed_light_events: Vec<TimedLightEvent, U64> = Vec::new();
-    helper_fill_events_slice(&test_durations, &mut timed_light_events);
+// The number after `--name`, if given
+fn flag_value<T: std::str::FromStr>(args: &[std::string::String], name: &str) -> Option<T> {
+    let i = args.iter().position(|a| a == name)?;
+    match args.get(i + 1).and_then(|n| n.parse().ok()) {
+        Some(value) => Some(value),
+        None => usage(),
+    }
+}
+
+// `--unit`, which has to be at least 1ms
+fn unit_flag(args: &[std::string::String]) -> Option<Time> {
+    match flag_value(args, "--unit") {
+        Some(unit) if unit <= 0 => usage(),
+        unit => unit,
+    }
+}
 
+// Flags are words, so notation like "--.-" isn't taken for one
+fn is_flag(arg: &str) -> bool {
+    match arg.strip_prefix("--") {
+        Some(name) => name.starts_with(|c: char| c.is_ascii_alphabetic()),
+        None => false,
+    }
+}
+
+// The arguments that aren't flags, joined by spaces
+fn positional(args: &[std::string::String], flags_with_values: &[&str]) -> std::string::String {
+    let mut words = std::vec::Vec::new();
+    let mut skip = false;
+    for arg in args {
+        if skip {
+            skip = false;
+        } else if flags_with_values.contains(&arg.as_str()) {
+            skip = true;
+        } else if !is_flag(arg) {
+            words.push(arg.as_str());
+        }
+    }
+    words.join(" ")
+}
+
+fn polarity_flag(args: &[std::string::String]) -> Polarity {
+    match flag_value::<std::string::String>(args, "--polarity").as_deref() {
+        None | Some("auto") => Polarity::Auto,
+        Some("normal") => Polarity::Normal,
+        Some("inverted") => Polarity::Inverted,
+        Some(_) => usage(),
+    }
+}
+
+// Prints the text, or why it was held back
+fn print_squelched(text: &str, detection: &Detection, squelch: i64) {
+    if detection.is_morse(squelch) {
+        println!("{}", text);
+    } else {
+        println!(
+            "(squelched, {}% sure this is Morse: {:?})",
+            detection.confidence, detection
+        );
+    }
+}
+
+fn main() {
+    let args: std::vec::Vec<std::string::String> = env::args().collect();
+    match args.get(1).map(|a| a.as_str()) {
+        None => demo(),
+        Some("video") => video(&args[2..]),
+        Some("split") => split(&args[2..]),
+        Some("encode") => encode(&args[2..]),
+        Some("decode") => decode(&args[2..]),
+        Some("timing") => timing(&args[2..]),
+        Some("notation") => notation(&args[2..]),
+        Some(_) => usage(),
+    }
+}
+
+#[allow(unused_assignments, unused_mut, unused_variables)]
+#[allow(clippy::empty_loop, clippy::single_match)]
+fn demo() {
+    let mut timed_light_events: Vec<TimedLightEvent, U64> = Vec::new();
+    helper_fill_events_slice(&test_durations, &mut timed_light_events);
 
     let expected: Scored<i64> = Scored {
         item: 100,
         score: 0,
     };
     match estimate_unit_time(&timed_light_events, 100, 110) {
-        Ok(actual) if expected == actual => {
-        },
-        Err(_) => loop {
-        },
-        _ => 
-        loop {
-        },
+        Ok(actual) if expected == actual => {}
+        Err(_) => loop {},
+        _ => loop {},
     };
 
- 
     let mut ttt: Vec<TimedLightEvent, U32> = Vec::new();
     match convert(&myint[0..], &mut ttt, 0) {
-        Err(_) => loop {
-        },
+        Err(_) => loop {},
         _ => (),
     };
 
     let r = estimate_unit_time(&ttt, 5, 6);
     let mut unwr;
     match r {
-        Err(_) => loop {
-        },
+        Err(_) => loop {},
         Ok(r) => unwr = r.item,
     }
 
-println!  ("{:?}",timed_light_events)   ;
+    println!("{:?}", timed_light_events);
+}
+
+fn video(args: &[std::string::String]) {
+    let path = match args.first() {
+        Some(path) => path,
+        None => usage(),
+    };
+    let seconds = args.iter().any(|a| a == "--seconds");
+    let report = args.iter().any(|a| a == "--report");
+    let squelch = flag_value(args, "--squelch").unwrap_or(DEFAULT_SQUELCH);
+
+    let contents = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+    match decode_brightness_csv(&contents, seconds) {
+        Ok(decoded) => {
+            println!("unit: {:.1}ms", decoded.unit_millis);
+            print_squelched(&decoded.text, &decoded.detection, squelch);
+            if report {
+                println!("{}", decoded.report);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn split(args: &[std::string::String]) {
+    let path = match args.first() {
+        Some(path) => path,
+        None => usage(),
+    };
+    let idle_gap = flag_value(args, "--idle").unwrap_or(DEFAULT_IDLE_GAP);
+    let squelch = flag_value(args, "--squelch").unwrap_or(DEFAULT_SQUELCH);
+    let polarity = polarity_flag(args);
+    let rescore = args.iter().any(|a| a == "--lm");
+    // One word per line, added to the built in English and ham words
+    let vocab_file = flag_value::<std::string::String>(args, "--vocab").map(|path| {
+        fs::read_to_string(&path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        })
+    });
+    let extra_words: std::vec::Vec<&str> = vocab_file
+        .as_deref()
+        .map(|words| words.split_whitespace().collect())
+        .unwrap_or_default();
+    let vocabularies = [ENGLISH, HAM, Vocabulary::new(&extra_words)];
+    let lm = LanguageModel::new(&vocabularies, CodeTable::standard());
+
+    let contents = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+    let samples = parse_capture(&contents);
+    if samples.is_empty() {
+        eprintln!("{}: no samples", path);
+        process::exit(1);
+    }
+
+    let config = SplitConfig {
+        idle_gap,
+        sample_gap: idle_gap / 4,
+        min_edges: 4,
+    };
+    let mut transmissions: Vec<Transmission, U64> = Vec::new();
+    if let Err(e) = split_transmissions(&samples, &config, &mut transmissions) {
+        eprintln!("{}: {:?}", path, e);
+        process::exit(1);
+    }
+
+    let mut events: Vec<TimedLightEvent, U4096> = Vec::new();
+    let mut scratch = Vec::new();
+    for t in transmissions.iter() {
+        print!("{}..{}: ", t.start, t.end);
+        let mut text: heapless::String<U1024> = heapless::String::new();
+        match decode_transmission(
+            &samples,
+            t,
+            &mut events,
+            &mut scratch,
+            (1, idle_gap / 2),
+            polarity,
+            &mut text,
+        ) {
+            Ok(decoded) => {
+                print!(
+                    "cutoffs {:?}, {:?}, unit {}: ",
+                    decoded.cutoffs.pair(),
+                    decoded.polarity,
+                    decoded.unit.item
+                );
+                let detection = detect_morse(
+                    &decoded.cutoffs,
+                    events.get(1..).unwrap_or(&[]),
+                    decoded.unit.item,
+                    &TimingModel::standard(),
+                );
+                print_squelched(&text, &detection, squelch);
+                if rescore && detection.is_morse(squelch) {
+                    print_rescored(&lm, events.get(1..).unwrap_or(&[]), decoded.unit.item);
+                }
+            }
+            Err(e) => println!("{:?}", e),
+        }
+    }
+}
+
+// The decode again with letters corrected to the nearest code, then words
+// rescored against the vocabularies, listing what changed
+fn print_rescored(lm: &LanguageModel, events: &[TimedLightEvent], unit_millis: Time) {
+    let mut letters: Vec<Correction, U1024> = Vec::new();
+    let mut text: heapless::String<U1024> = heapless::String::new();
+    let mut changes: Vec<Change, U64> = Vec::new();
+    let rescored = decode_corrected(events, unit_millis, &mut letters)
+        .and_then(|_| lm.rescore(&letters, unit_millis, &mut text, &mut changes));
+    match rescored {
+        Ok(()) => {
+            println!("  rescored: {}", text);
+            for change in changes.iter() {
+                println!(
+                    "    word {}: {} -> {} ({:?}, cost {})",
+                    change.word, change.from, change.to, change.reason, change.cost
+                );
+            }
+        }
+        Err(e) => println!("  rescoring failed: {:?}", e),
+    }
+}
+
+fn parse_sequence(notation: &str) -> Sequence<U1024> {
+    notation.parse().unwrap_or_else(|e| {
+        eprintln!("{}: {:?}", notation, e);
+        process::exit(1);
+    })
+}
+
+// Text to dot-dash notation
+fn encode(args: &[std::string::String]) {
+    let text = positional(args, &[]);
+    let mut symbols: Vec<Morse, U1024> = Vec::new();
+    if let Err(e) = encode_text(&text, &CodeTable::standard(), &mut symbols) {
+        eprintln!("{}: {:?}", text, e);
+        process::exit(1);
+    }
+    println!("{}", Notation(&symbols));
+}
+
+// Dot-dash notation to text
+fn decode(args: &[std::string::String]) {
+    let sequence = parse_sequence(&positional(args, &[]));
+    let text: std::string::String = sequence
+        .0
+        .iter()
+        .copied()
+        .chars(CodeTable::standard())
+        .collect();
+    println!("{}", text);
+}
+
+// Dot-dash notation to events, one per line as read by `notation`
+fn timing(args: &[std::string::String]) {
+    let unit = unit_flag(args).unwrap_or(DEFAULT_UNIT);
+    let notation = positional(args, &["--unit"]);
+    let sequence = parse_sequence(&notation);
+    let mut events: Vec<TimedLightEvent, U1024> = Vec::new();
+    if let Err(e) = time_symbols(&sequence.0, unit, &TimingModel::standard(), &mut events) {
+        eprintln!("{}: {:?}", notation, e);
+        process::exit(1);
+    }
+    for event in events.iter() {
+        println!("{}", format_timing(event));
+    }
+}
+
+// Events back to dot-dash notation and text, estimating the unit if it isn't
+// given
+fn notation(args: &[std::string::String]) {
+    let path = match positional(args, &["--unit"]) {
+        path if path.is_empty() => usage(),
+        path => path,
+    };
+    let contents = fs::read_to_string(&path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+    let events = parse_timings(&contents);
+    let longest = events.iter().map(|e| e.duration).max().unwrap_or(0);
+    let unit = match unit_flag(args) {
+        Some(unit) => unit,
+        None => match estimate_unit_time(&events, 1, longest + 1) {
+            Ok(unit) => unit.item,
+            Err(e) => {
+                eprintln!("{}: {:?}", path, e);
+                process::exit(1);
+            }
+        },
+    };
+    let symbols: std::vec::Vec<Morse> = events.iter().copied().symbols(unit).collect();
+    let text: std::string::String = symbols
+        .iter()
+        .copied()
+        .chars(CodeTable::standard())
+        .collect();
+    println!("unit: {}", unit);
+    println!("{}", Notation(&symbols));
+    println!("{}", text);
+}
+
+fn format_timing(event: &TimedLightEvent) -> std::string::String {
+    match event.light_state {
+        LightState::Light => format!("light {}", event.duration),
+        LightState::Dark => format!("dark {}", event.duration),
+    }
+}
+
+// Reads one event per line, "light <duration>" or "dark <duration>". Lines
+// that don't parse are skipped.
+fn parse_timings(contents: &str) -> std::vec::Vec<TimedLightEvent> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let light_state = match fields.next()? {
+                "light" => LightState::Light,
+                "dark" => LightState::Dark,
+                _ => return None,
+            };
+            let duration = fields.next()?.parse().ok()?;
+            Some(TimedLightEvent {
+                light_state,
+                duration,
+            })
+        })
+        .collect()
+}
+
+// Reads one sample per line, either "time,intensity" or a bare intensity
+// that's timed by its line number. Intensities that don't fit a
+// `LightIntensity` are skipped like any other line that doesn't parse.
+fn parse_capture(contents: &str) -> std::vec::Vec<(Time, LightIntensity)> {
+    contents
+        .lines()
+        .map(|line| line.split(|c: char| c == ',' || c.is_whitespace()))
+        .map(|fields| fields.filter(|f| !f.is_empty()))
+        .filter_map(|mut fields| {
+            let first = fields.next()?;
+            match fields.next() {
+                Some(second) => Some((Some(first.parse().ok()?), second.parse().ok()?)),
+                None => Some((None, first.parse().ok()?)),
+            }
+        })
+        .enumerate()
+        .map(|(i, (time, li))| (time.unwrap_or(i as Time), li))
+        .collect()
+}
+
+// Reads "timestamp,brightness" rows, one per video frame. Rows that don't
+// parse, like a header, are skipped.
+fn parse_brightness_csv(contents: &str, seconds: bool) -> std::vec::Vec<(f64, f64)> {
+    let time_scale = if seconds { 1000.0 } else { 1.0 };
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(',').map(str::trim);
+            let time: f64 = fields.next()?.parse().ok()?;
+            let brightness: f64 = fields.next()?.parse().ok()?;
+            Some((time * time_scale, brightness))
+        })
+        .collect()
+}
+
+struct VideoDecode {
+    unit_millis: f64,
+    text: std::string::String,
+    report: SignalReport,
+    detection: Detection,
+}
+
+// Decodes a per-frame brightness trace of a signal lamp
+fn decode_brightness_csv(
+    contents: &str,
+    seconds: bool,
+) -> Result<VideoDecode, std::string::String> {
+    let frames = parse_brightness_csv(contents, seconds);
+    if frames.len() < 2 {
+        return Err("need at least two frames".into());
+    }
+
+    let lowest = frames.iter().map(|f| f.1).fold(f64::INFINITY, f64::min);
+    let highest = frames.iter().map(|f| f.1).fold(f64::NEG_INFINITY, f64::max);
+    if highest <= lowest {
+        return Err("brightness never changes".into());
+    }
+    // Frame times are kept at the edge resolution, since at 24fps they're
+    // rarely whole milliseconds
+    let samples: std::vec::Vec<(Time, LightIntensity)> = frames
+        .iter()
+        .map(|(time, brightness)| {
+            let level = (brightness - lowest) / (highest - lowest) * VIDEO_FULL_SCALE;
+            (
+                (time * VIDEO_RESOLUTION as f64).round() as Time,
+                level.round() as LightIntensity,
+            )
+        })
+        .collect();
+
+    let mut frame_gaps: std::vec::Vec<Time> = samples.windows(2).map(|w| w[1].0 - w[0].0).collect();
+    frame_gaps.sort_unstable();
+    let frame = frame_gaps[frame_gaps.len() / 2].max(1);
+
+    // At 24-60fps a transition is smeared over the frame it lands in, so place
+    // edges between frames rather than on them. The report below describes
+    // these same cutoffs.
+    let cutoffs =
+        calc_digital_cutoffs_by(&samples, ThresholdMethod::Otsu).map_err(|e| format!("{:?}", e))?;
+    let mut events: Vec<TimedLightEvent, U2048> = Vec::new();
+    convert_with_cutoffs(&samples, &mut events, samples[0].0, cutoffs.pair(), Some(1))
+        .map_err(|e| format!("{:?}", e))?;
+    // The first event is the wait before the lamp first comes on
+    let events = events.get(1..).unwrap_or(&[]);
+
+    // Being a frame off is expected, so errors inside a frame count for little
+    let jitter = Huber { delta: frame };
+    let model = TimingModel::standard();
+    let mut scratch: Vec<TimedLightEvent, U2048> = Vec::new();
+    // Anything much shorter than a frame can't be seen reliably
+    let unit = estimate_unit_time_clustered_by(
+        events,
+        &mut scratch,
+        frame / 2,
+        2000 * VIDEO_RESOLUTION,
+        &model,
+        &jitter,
+    )
+    .map_err(|e| format!("{:?}", e))?;
+
+    let mut text: heapless::String<U1024> = heapless::String::new();
+    decode_events_by(events, unit.item, &model, &jitter, &mut text)
+        .map_err(|e| format!("{:?}", e))?;
+
+    // The report is in whole milliseconds
+    let millis: std::vec::Vec<TimedLightEvent> = events
+        .iter()
+        .map(|tle| TimedLightEvent {
+            duration: tle.duration / VIDEO_RESOLUTION,
+            ..*tle
+        })
+        .collect();
+    let report = analyze(&cutoffs, &millis, unit.item / VIDEO_RESOLUTION, &model);
+    let detection = detect_morse(&cutoffs, &millis, unit.item / VIDEO_RESOLUTION, &model);
+
+    Ok(VideoDecode {
+        unit_millis: unit.item as f64 / VIDEO_RESOLUTION as f64,
+        text: text.as_str().into(),
+        report,
+        detection,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_capture() {
+        assert_eq!(
+            std::vec![(0, 900), (1, 899), (2, 410)],
+            parse_capture("900\n899\n\n410\n")
+        );
+        assert_eq!(
+            std::vec![(5, 50), (10, 500)],
+            parse_capture("time,level\n5,50\n10 500\n")
+        );
+        // Out of range levels are dropped rather than wrapped
+        assert_eq!(
+            std::vec![(0, 300), (7, 65535)],
+            parse_capture("70000\n-5\n300\n5,-1\n6,65536\n7,65535\n")
+        );
+    }
+
+    #[test]
+    fn test_parse_timings() {
+        let events = parse_timings("light 300\n# comment\ndark 100\n\nlight x\nlight 100\n");
+        assert_eq!(3, events.len());
+        assert_eq!(LightState::Dark, events[1].light_state);
+        assert_eq!(100, events[2].duration);
+        let formatted: std::vec::Vec<_> = events.iter().map(format_timing).collect();
+        assert_eq!(events, parse_timings(&formatted.join("\n")));
+    }
+
+    #[test]
+    fn test_decode_brightness_csv() {
+        // "SOS SOS" at 90ms per unit filmed at 24fps, timestamps in seconds
+        let sos = [1, 1, 1, 1, 1, 3, 3, 1, 3, 1, 3, 3, 1, 1, 1, 1, 1];
+        let mut lit = std::vec::Vec::new();
+        let mut time = 400.0;
+        for _ in 0..2 {
+            for (i, units) in sos.iter().enumerate() {
+                let end = time + 90.0 * *units as f64;
+                if i % 2 == 0 {
+                    lit.push((time, end));
+                }
+                time = end;
+            }
+            time += 7.0 * 90.0;
+        }
+
+        let frame = 1000.0 / 24.0;
+        let mut csv = std::string::String::from("t,roi_mean\n");
+        for i in 0..(time / frame) as usize + 10 {
+            let (start, end) = (i as f64 * frame, (i + 1) as f64 * frame);
+            let overlap: f64 = lit
+                .iter()
+                .map(|(on, off): &(f64, f64)| (end.min(*off) - start.max(*on)).max(0.0))
+                .sum();
+            let brightness = 31.5 + 180.0 * overlap / frame;
+            csv.push_str(&format!("{:.4},{:.2}\n", start / 1000.0, brightness));
+        }
+
+        let decoded = decode_brightness_csv(&csv, true).unwrap();
+        assert_eq!("SOS SOS", decoded.text);
+        assert!((decoded.unit_millis - 90.0).abs() < 1.0);
+        // Frame smearing leaves every edge within a frame or so
+        assert_eq!(0, decoded.report.poor_fits);
+        assert!(decoded.detection.is_morse(DEFAULT_SQUELCH));
+    }
 }
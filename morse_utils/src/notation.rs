@@ -0,0 +1,290 @@
+// Morse written out as text, ".- -... / -.-." for "AB C". Within a letter a
+// dot is '.' and a dash '-', with the American long dash as '_' and its wider
+// inner space as '~'. Letters are separated by whitespace and words by '/'.
+// An element that couldn't be read is '?'.
+//
+// Parsed sequences come out the same as from `EventIterator::symbols`, with
+// a `TinySpace` between elements of a letter, so they can be timed back into
+// events with `time_symbols` or decoded with `SymbolIterator::chars`.
+
+use crate::{CodeTable, Morse, Time, TimedLightEvent, TimingModel};
+use core::fmt;
+use core::str::FromStr;
+use heapless::{ArrayLength, Vec};
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum NotationErr {
+    // A character that isn't part of the notation, and its byte offset
+    Unexpected(char, usize),
+    // A letter that isn't in the code table
+    NoCode(char),
+    // A symbol the timing model has no length for
+    Untimed(Morse),
+    OutputFull,
+}
+
+impl fmt::Display for Morse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Morse::Dot => ".",
+            Morse::Dash => "-",
+            Morse::LongDash => "_",
+            Morse::Error => "?",
+            Morse::TinySpace => "",
+            Morse::InnerSpace => "~",
+            Morse::LetterSpace => " ",
+            Morse::WordSpace => " / ",
+        })
+    }
+}
+
+// Symbols formatted in the notation, e.g. `println!("{}", Notation(&symbols))`
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Notation<'a>(pub &'a [Morse]);
+
+impl<'a> fmt::Display for Notation<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for symbol in self.0 {
+            write!(f, "{}", symbol)?;
+        }
+        Ok(())
+    }
+}
+
+// An owned sequence of symbols, for parsing with `str::parse`
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Sequence<C: ArrayLength<Morse>>(pub Vec<Morse, C>);
+
+impl<C: ArrayLength<Morse>> FromStr for Sequence<C> {
+    type Err = NotationErr;
+
+    fn from_str(notation: &str) -> Result<Self, NotationErr> {
+        let mut symbols = Vec::new();
+        parse_notation(notation, &mut symbols)?;
+        Ok(Sequence(symbols))
+    }
+}
+
+impl<C: ArrayLength<Morse>> fmt::Display for Sequence<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Notation(&self.0).fmt(f)
+    }
+}
+
+fn push<C>(symbols: &mut Vec<Morse, C>, symbol: Morse) -> Result<(), NotationErr>
+where
+    C: ArrayLength<Morse>,
+{
+    symbols.push(symbol).map_err(|_| NotationErr::OutputFull)
+}
+
+// Spaces and slashes before the first letter and after the last are dropped,
+// and runs of them collapse to the widest, so anything parsed formats back the
+// same way `Notation` writes it
+pub fn parse_notation<C>(notation: &str, symbols: &mut Vec<Morse, C>) -> Result<(), NotationErr>
+where
+    C: ArrayLength<Morse>,
+{
+    // Whether the last symbol was an element, which needs a space before the
+    // next one in the same letter
+    let mut in_letter = false;
+    let mut gap: Option<Morse> = None;
+    for (i, c) in notation.char_indices() {
+        let element = match c {
+            '.' => Morse::Dot,
+            '-' => Morse::Dash,
+            '_' => Morse::LongDash,
+            '?' => Morse::Error,
+            '~' if in_letter => {
+                push(symbols, Morse::InnerSpace)?;
+                in_letter = false;
+                continue;
+            }
+            '/' => {
+                if !symbols.is_empty() {
+                    gap = Some(Morse::WordSpace);
+                }
+                in_letter = false;
+                continue;
+            }
+            c if c.is_whitespace() => {
+                if in_letter {
+                    gap = Some(Morse::LetterSpace);
+                }
+                in_letter = false;
+                continue;
+            }
+            c => return Err(NotationErr::Unexpected(c, i)),
+        };
+        if in_letter {
+            push(symbols, Morse::TinySpace)?;
+        } else if let Some(gap) = gap.take() {
+            push(symbols, gap)?;
+        }
+        push(symbols, element)?;
+        in_letter = true;
+    }
+    Ok(())
+}
+
+// The symbols for `text`, with letters looked up in `table` ignoring case.
+// Whitespace of any length separates words.
+pub fn encode_text<C>(
+    text: &str,
+    table: &CodeTable,
+    symbols: &mut Vec<Morse, C>,
+) -> Result<(), NotationErr>
+where
+    C: ArrayLength<Morse>,
+{
+    for (i, word) in text.split_whitespace().enumerate() {
+        if i > 0 {
+            push(symbols, Morse::WordSpace)?;
+        }
+        for (j, c) in word.chars().enumerate() {
+            let code = table
+                .code(c.to_ascii_uppercase())
+                .ok_or(NotationErr::NoCode(c))?;
+            if j > 0 {
+                push(symbols, Morse::LetterSpace)?;
+            }
+            for (k, b) in code.bytes().enumerate() {
+                if k > 0 {
+                    push(symbols, Morse::TinySpace)?;
+                }
+                push(symbols, if b == b'-' { Morse::Dash } else { Morse::Dot })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Each symbol as an event of the length `model` expects at `unit_millis`
+pub fn time_symbols<C>(
+    symbols: &[Morse],
+    unit_millis: Time,
+    model: &TimingModel,
+    events: &mut Vec<TimedLightEvent, C>,
+) -> Result<(), NotationErr>
+where
+    C: ArrayLength<TimedLightEvent>,
+{
+    for symbol in symbols {
+        let element = model
+            .elements
+            .iter()
+            .find(|e| e.morse == *symbol)
+            .ok_or(NotationErr::Untimed(*symbol))?;
+        events
+            .push(TimedLightEvent {
+                light_state: element.candidate.light_state,
+                duration: model.expected_duration(&element.candidate, unit_millis),
+            })
+            .map_err(|_| NotationErr::OutputFull)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::decode_events;
+    use crate::iter::{EventIterator, SymbolIterator};
+    use heapless::consts::*;
+    use heapless::String;
+    use Morse::*;
+
+    #[test]
+    fn test_parse_and_format() {
+        let sequence: Sequence<U32> = ".- -... / -.-.".parse().unwrap();
+        assert_eq!(
+            [
+                Dot,
+                TinySpace,
+                Dash,
+                LetterSpace,
+                Dash,
+                TinySpace,
+                Dot,
+                TinySpace,
+                Dot,
+                TinySpace,
+                Dot,
+                WordSpace,
+                Dash,
+                TinySpace,
+                Dot,
+                TinySpace,
+                Dash,
+                TinySpace,
+                Dot,
+            ],
+            sequence.0[..]
+        );
+        assert_eq!(".- -... / -.-.", std::format!("{}", sequence));
+
+        // Extra separators collapse and the ends are trimmed
+        let sequence: Sequence<U16> = " / ..\t -  /  / .?  ".parse().unwrap();
+        assert_eq!(".. - / .?", std::format!("{}", sequence));
+        let sequence: Sequence<U16> = ".._ ..~.".parse().unwrap();
+        assert_eq!(".._ ..~.", std::format!("{}", sequence));
+
+        assert_eq!(
+            Err(NotationErr::Unexpected('x', 3)),
+            ".- x".parse::<Sequence<U16>>()
+        );
+        assert_eq!(
+            Err(NotationErr::Unexpected('~', 0)),
+            "~.".parse::<Sequence<U16>>()
+        );
+        assert_eq!(
+            Err(NotationErr::OutputFull),
+            "... ---".parse::<Sequence<U8>>()
+        );
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let table = CodeTable::standard();
+        let mut symbols: Vec<Morse, U64> = Vec::new();
+        encode_text("sos  Help", &table, &mut symbols).unwrap();
+        assert_eq!(
+            "... --- ... / .... . .-.. .--.",
+            std::format!("{}", Notation(&symbols))
+        );
+        let mut text: String<U16> = String::new();
+        for c in symbols.iter().copied().chars(table) {
+            text.push(c).unwrap();
+        }
+        assert_eq!("SOS HELP", text.as_str());
+
+        let mut symbols: Vec<Morse, U64> = Vec::new();
+        assert_eq!(
+            Err(NotationErr::NoCode('#')),
+            encode_text("A#", &table, &mut symbols)
+        );
+    }
+
+    #[test]
+    fn test_timing_round_trip() {
+        let model = TimingModel::standard();
+        let sequence: Sequence<U32> = "-.-. --.- / - . ... -".parse().unwrap();
+        let mut events: Vec<TimedLightEvent, U32> = Vec::new();
+        time_symbols(&sequence.0, 80, &model, &mut events).unwrap();
+        assert_eq!(240, events[0].duration);
+        assert_eq!(80, events[1].duration);
+
+        let mut text: String<U16> = String::new();
+        decode_events(&events, 80, &mut text).unwrap();
+        assert_eq!("CQ TEST", text.as_str());
+        let symbols: Vec<Morse, U32> = events.iter().copied().symbols(80).collect();
+        assert_eq!(sequence.0, symbols);
+
+        assert_eq!(
+            Err(NotationErr::Untimed(LongDash)),
+            time_symbols(&[LongDash], 80, &model, &mut events)
+        );
+    }
+}
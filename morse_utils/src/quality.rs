@@ -0,0 +1,324 @@
+// Diagnostics for a capture, to tell a decode that failed because the light
+// levels were murky apart from one that failed because the sender's timing
+// was off.
+
+use crate::time::TickCount;
+use crate::{
+    best_error_by, isqrt, log2_fixed, Absolute, Cutoffs, LightIntensity, Morse, Time,
+    TimedLightEvent, TimingModel,
+};
+use core::fmt;
+use heapless::consts::U8;
+use heapless::Vec;
+
+// An event is a poor fit when it's off from its candidate by more than this
+// share of the candidate's length, in hundredths
+const POOR_FIT_PERCENT: Time = 25;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct DurationStats {
+    pub morse: Morse,
+    pub count: u32,
+    pub mean: Time,
+    pub stddev: Time,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SignalReport {
+    pub dark_level: LightIntensity,
+    pub light_level: LightIntensity,
+    pub dark_noise: LightIntensity,
+    pub light_noise: LightIntensity,
+    // Light level over dark level, in hundredths
+    pub contrast: i64,
+    // Level difference over the pooled noise, in tenths of a dB
+    pub snr: i64,
+    pub unit_millis: Time,
+    pub events: u32,
+    pub poor_fits: u32,
+    // One entry per symbol class seen, in the order they first appear
+    pub durations: Vec<DurationStats, U8>,
+}
+
+impl SignalReport {
+    // Share of events that fit poorly, in thousandths
+    pub fn poor_fit_permille(&self) -> i64 {
+        self.poor_fits as i64 * 1000 / (self.events as i64).max(1)
+    }
+}
+
+// 20 log10(separation / 100) in tenths of a dB, where log10(2) ~= 0.30103
+fn snr_from_separation(separation: i64) -> i64 {
+    (log2_fixed(separation) - log2_fixed(100)) * 60206 / 1_024_000
+}
+
+// The report's durations are in the ticks of `D`
+pub fn analyze<D: TickCount>(
+    cutoffs: &Cutoffs,
+    timings: &[TimedLightEvent<D>],
+    unit_millis: D,
+    model: &TimingModel,
+) -> SignalReport {
+    // Per class: count, sum and sum of squares of the durations
+    let mut sums: Vec<(Morse, u32, i64, i64), U8> = Vec::new();
+    let mut poor_fits = 0;
+    for tle in timings {
+        let best = match best_error_by(tle, unit_millis, model, &Absolute) {
+            Ok(best) => best,
+            Err(_) => continue,
+        };
+        let expected = model.expected_duration(best.item, unit_millis.ticks());
+        if best.score * 100 > expected * POOR_FIT_PERCENT {
+            poor_fits += 1;
+        }
+
+        let morse = model.morse(best.item);
+        let index = match sums.iter().position(|s| s.0 == morse) {
+            Some(index) => index,
+            None => {
+                // Models have at most a handful of classes
+                if sums.push((morse, 0, 0, 0)).is_err() {
+                    continue;
+                }
+                sums.len() - 1
+            }
+        };
+        let d = tle.duration.ticks();
+        let s = &mut sums[index];
+        s.1 += 1;
+        s.2 += d;
+        s.3 += d * d;
+    }
+
+    let mut durations = Vec::new();
+    for (morse, count, sum, squares) in sums.iter() {
+        let n = *count as i64;
+        let mean = sum / n;
+        let variance = (squares / n - mean * mean).max(0);
+        let _ = durations.push(DurationStats {
+            morse: *morse,
+            count: *count,
+            mean,
+            stddev: isqrt(variance as u64) as Time,
+        });
+    }
+
+    SignalReport {
+        dark_level: cutoffs.dark_mean,
+        light_level: cutoffs.light_mean,
+        dark_noise: cutoffs.dark_stddev,
+        light_noise: cutoffs.light_stddev,
+        contrast: cutoffs.light_mean as i64 * 100 / (cutoffs.dark_mean as i64).max(1),
+        snr: snr_from_separation(cutoffs.separation),
+        unit_millis: unit_millis.ticks(),
+        events: timings.len() as u32,
+        poor_fits,
+        durations,
+    }
+}
+
+// Confidence below which output should be squelched by default
+pub const DEFAULT_SQUELCH: i64 = 50;
+// Fewer events than this could fit the timing model by chance
+const MIN_EVENTS: i64 = 12;
+
+// How sure we are that a stretch of signal is Morse at all, along with the
+// parts that went into it. Each part is a percentage.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct Detection {
+    pub confidence: i64,
+    // How closely the durations sit on the model's ratios
+    pub timing: i64,
+    // Whether both element lengths and more than one gap length turn up, which
+    // a steadily flickering lamp never manages
+    pub variety: i64,
+    pub length: i64,
+    pub contrast: i64,
+}
+
+impl Detection {
+    pub fn is_morse(&self, squelch: i64) -> bool {
+        self.confidence >= squelch
+    }
+}
+
+pub fn detect_morse<D: TickCount>(
+    cutoffs: &Cutoffs,
+    timings: &[TimedLightEvent<D>],
+    unit_millis: D,
+    model: &TimingModel,
+) -> Detection {
+    let mut relative_error = 0;
+    let mut seen: Vec<Morse, U8> = Vec::new();
+    for tle in timings {
+        let best = match best_error_by(tle, unit_millis, model, &Absolute) {
+            Ok(best) => best,
+            Err(_) => continue,
+        };
+        let expected = model
+            .expected_duration(best.item, unit_millis.ticks())
+            .max(1);
+        relative_error += (best.score * 1000 / expected).min(1000);
+        let morse = model.morse(best.item);
+        if !seen.contains(&morse) {
+            let _ = seen.push(morse);
+        }
+    }
+
+    let events = timings.len() as i64;
+    // Random durations average over 25% off, a hand sender well under 10%
+    let timing = (100 - relative_error / events.max(1) * 2 / 5).clamp(0, 100);
+    let classes = |light: bool| {
+        let is_light = |m: &&Morse| matches!(m, Morse::Dot | Morse::Dash | Morse::LongDash);
+        seen.iter().filter(|m| is_light(m) == light).count()
+    };
+    let variety =
+        30 + if classes(true) > 1 { 35 } else { 0 } + if classes(false) > 1 { 35 } else { 0 };
+    let length = (events * 100 / MIN_EVENTS).min(100);
+    // No confidence at 2 standard deviations apart, full confidence at 6
+    let contrast = ((cutoffs.separation - 200) / 4).clamp(0, 100);
+
+    Detection {
+        confidence: timing * variety / 100 * length / 100 * contrast / 100,
+        timing,
+        variety,
+        length,
+        contrast,
+    }
+}
+
+// Writes `x` hundredths or tenths with its decimal point
+fn write_fixed(f: &mut fmt::Formatter, x: i64, scale: i64) -> fmt::Result {
+    let sign = if x < 0 { "-" } else { "" };
+    let x = x.abs();
+    match scale {
+        100 => write!(f, "{}{}.{:02}", sign, x / 100, x % 100),
+        _ => write!(f, "{}{}.{}", sign, x / scale, x % scale),
+    }
+}
+
+impl fmt::Display for SignalReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "levels: dark {} +/- {}, light {} +/- {}",
+            self.dark_level, self.dark_noise, self.light_level, self.light_noise
+        )?;
+        write!(f, "contrast: ")?;
+        write_fixed(f, self.contrast, 100)?;
+        write!(f, ":1, snr: ")?;
+        write_fixed(f, self.snr, 10)?;
+        writeln!(f, "dB")?;
+        write!(
+            f,
+            "unit: {}, {} of {} events fit poorly (",
+            self.unit_millis, self.poor_fits, self.events
+        )?;
+        write_fixed(f, self.poor_fit_permille(), 10)?;
+        write!(f, "%)")?;
+        for d in self.durations.iter() {
+            write!(
+                f,
+                "\n{:?}: {} x {} +/- {}",
+                d.morse, d.count, d.mean, d.stddev
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::tests::helper_fill_alternating;
+    use crate::{calc_digital_cutoffs_by, ThresholdMethod};
+    use heapless::consts::*;
+    use std::string::ToString;
+
+    #[test]
+    fn test_analyze() {
+        let mut intensities: Vec<(Time, LightIntensity), U64> = Vec::new();
+        for i in 0..40 {
+            let noise = [0, 20, 10, 30][i % 4];
+            let level = if i % 2 == 0 { 100 } else { 900 };
+            intensities.push((i as Time, level + noise)).unwrap();
+        }
+        let cutoffs = calc_digital_cutoffs_by(&intensities, ThresholdMethod::Otsu).unwrap();
+
+        // "AN" with a wobbly hand and one dash that runs far too long
+        let timings =
+            helper_fill_alternating(&[95, 105, 310, 290, 290, 110, 100, 700, 450, 100, 105]);
+        let report = analyze(&cutoffs, &timings, 100, &TimingModel::standard());
+
+        assert_eq!(105, report.dark_level);
+        assert_eq!(925, report.light_level);
+        assert_eq!(880, report.contrast);
+        // 820 apart with 5 of noise either side is 20 log10(164)
+        assert_eq!(442, report.snr);
+        assert_eq!(1, report.poor_fits);
+        assert_eq!(90, report.poor_fit_permille());
+
+        let dots = report.durations.iter().find(|d| d.morse == Morse::Dot);
+        assert_eq!(
+            Some(&DurationStats {
+                morse: Morse::Dot,
+                count: 3,
+                mean: 100,
+                stddev: 4,
+            }),
+            dots
+        );
+        let dashes = report.durations.iter().find(|d| d.morse == Morse::Dash);
+        assert_eq!(Some(350), dashes.map(|d| d.mean));
+
+        let printed = report.to_string();
+        assert!(printed.contains("contrast: 8.80:1"), "{}", printed);
+        assert!(printed.contains("1 of 11 events fit poorly (9.0%)"));
+    }
+
+    #[test]
+    fn test_detect_morse() {
+        let mut intensities: Vec<(Time, LightIntensity), U64> = Vec::new();
+        for i in 0..40 {
+            let level = if i % 2 == 0 { 100 } else { 900 };
+            intensities
+                .push((i as Time, level + (i % 3) as u16 * 20))
+                .unwrap();
+        }
+        let cutoffs = calc_digital_cutoffs_by(&intensities, ThresholdMethod::Otsu).unwrap();
+        let model = TimingModel::standard();
+
+        // "PARIS" with a slightly shaky hand
+        let paris = helper_fill_alternating(&[
+            105, 95, 290, 110, 310, 100, 90, 280, 100, 105, 300, 310, 95, 105, 290, 90, 110, 300,
+            100, 90, 95, 320, 100, 100, 105, 110, 95,
+        ]);
+        let detection = detect_morse(&cutoffs, &paris, 100, &model);
+        assert!(detection.is_morse(DEFAULT_SQUELCH), "{:?}", detection);
+
+        // A lamp flickering on and off evenly fits dots perfectly
+        let flicker = helper_fill_alternating(&[100; 30]);
+        let detection = detect_morse(&cutoffs, &flicker, 100, &model);
+        assert_eq!(100, detection.timing);
+        assert!(!detection.is_morse(DEFAULT_SQUELCH), "{:?}", detection);
+
+        // Noise fits whatever unit poorly
+        let mut seed: i64 = 7;
+        let mut random = [0; 30];
+        for r in random.iter_mut() {
+            seed = (seed * 1103515245 + 12345) % (1 << 31);
+            *r = 30 + seed % 870;
+        }
+        let noise = helper_fill_alternating(&random);
+        let mut scratch: Vec<TimedLightEvent, U64> = Vec::new();
+        let unit = crate::estimate_unit_time_clustered(&noise, &mut scratch, 10, 500).unwrap();
+        let detection = detect_morse(&cutoffs, &noise, unit.item, &model);
+        assert!(!detection.is_morse(DEFAULT_SQUELCH), "{:?}", detection);
+
+        // Too short to tell
+        let detection = detect_morse(&cutoffs, &paris[..5], 100, &model);
+        assert!(!detection.is_morse(DEFAULT_SQUELCH), "{:?}", detection);
+    }
+}
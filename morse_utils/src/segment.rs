@@ -0,0 +1,271 @@
+// Splitting long captures into separate transmissions. Left whole, the idle
+// stretches between transmissions turn into giant events that drag the unit
+// estimate around, and the light levels can drift from one transmission to
+// the next.
+
+use crate::time::TickCount;
+use crate::{
+    calc_digital_cutoffs_by, convert_with_polarity, decode_events, estimate_unit_time_clustered,
+    Cutoffs, LightIntensity, MorseErr, Polarity, Scored, ThresholdMethod, Time, TimedLightEvent,
+};
+use core::ops::Range;
+use heapless::{ArrayLength, String, Vec};
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct SplitConfig {
+    // A level held for longer than this ends the transmission
+    pub idle_gap: Time,
+    // Consecutive samples further apart than this mean the signal was lost
+    pub sample_gap: Time,
+    // Transmissions with fewer edges than this are dropped as blips
+    pub min_edges: usize,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Transmission {
+    // Times of the first and last edge
+    pub start: Time,
+    pub end: Time,
+    // The samples covering it, from the one before the first edge up to the
+    // one that made the last edge
+    pub samples: Range<usize>,
+    pub edges: usize,
+}
+
+struct Open {
+    lead_in: usize,
+    start: Time,
+    last: usize,
+    end: Time,
+    edges: usize,
+}
+
+fn close<C>(
+    open: &mut Option<Open>,
+    min_edges: usize,
+    transmissions: &mut Vec<Transmission, C>,
+) -> Result<(), MorseErr>
+where
+    C: ArrayLength<Transmission>,
+{
+    match open.take() {
+        Some(o) if o.edges >= min_edges => transmissions
+            .push(Transmission {
+                start: o.start,
+                end: o.end,
+                samples: o.lead_in..o.last + 1,
+                edges: o.edges,
+            })
+            .map_err(|_| MorseErr::OutputFull),
+        _ => Ok(()),
+    }
+}
+
+// Finds the transmissions in a capture. Idle is whichever level is held for
+// longer than `idle_gap`, so this works the same whether the key lights or
+// darkens the sensor.
+pub fn split_transmissions<C>(
+    intensities: &[(Time, LightIntensity)],
+    config: &SplitConfig,
+    transmissions: &mut Vec<Transmission, C>,
+) -> Result<(), MorseErr>
+where
+    C: ArrayLength<Transmission>,
+{
+    let cutoffs = calc_digital_cutoffs_by(intensities, ThresholdMethod::Otsu)?;
+    let (first_time, first_light) = intensities[0];
+    let mut high = first_light > cutoffs.threshold;
+    let mut run_start = first_time;
+    let mut prev_time = first_time;
+    let mut resumed = 0;
+    let mut open: Option<Open> = None;
+
+    for (i, (time, light)) in intensities.iter().enumerate() {
+        if *time - prev_time > config.sample_gap {
+            // Keep the level from before the gap, so coming back on the
+            // other level counts as an edge
+            close(&mut open, config.min_edges, transmissions)?;
+            resumed = i;
+            run_start = *time;
+        }
+        prev_time = *time;
+
+        let edge = match high {
+            false => *light > cutoffs.high,
+            true => *light < cutoffs.low,
+        };
+        if !edge {
+            continue;
+        }
+        if *time - run_start > config.idle_gap {
+            close(&mut open, config.min_edges, transmissions)?;
+        }
+        match open.as_mut() {
+            Some(o) => {
+                o.last = i;
+                o.end = *time;
+                o.edges += 1;
+            }
+            None => {
+                open = Some(Open {
+                    lead_in: i.saturating_sub(1).max(resumed),
+                    start: *time,
+                    last: i,
+                    end: *time,
+                    edges: 1,
+                })
+            }
+        }
+        high = !high;
+        run_start = *time;
+    }
+    close(&mut open, config.min_edges, transmissions)
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct DecodedTransmission<D = Time> {
+    pub cutoffs: Cutoffs,
+    pub polarity: Polarity,
+    pub unit: Scored<D>,
+}
+
+// Decodes one transmission on its own, with cutoffs and a unit estimate from
+// only its samples. With `Polarity::Auto` each transmission picks its own.
+// `events` and `scratch` are emptied first, and the events come out in the
+// ticks of `D`.
+pub fn decode_transmission<C, T, D>(
+    intensities: &[(Time, LightIntensity)],
+    transmission: &Transmission,
+    events: &mut Vec<TimedLightEvent<D>, C>,
+    scratch: &mut Vec<TimedLightEvent<D>, C>,
+    (min_millis, max_millis): (D, D),
+    polarity: Polarity,
+    text: &mut String<T>,
+) -> Result<DecodedTransmission<D>, MorseErr>
+where
+    C: ArrayLength<TimedLightEvent<D>>,
+    T: ArrayLength<u8>,
+    D: TickCount,
+{
+    let samples = &intensities[transmission.samples.clone()];
+    let cutoffs = calc_digital_cutoffs_by(samples, ThresholdMethod::Otsu)?;
+    let polarity = convert_with_polarity(
+        samples,
+        events,
+        samples[0].0,
+        cutoffs.pair(),
+        None,
+        polarity,
+        scratch,
+    )?;
+
+    // The first event is the lead-in from the sample before the first edge
+    let events = events.get(1..).unwrap_or(&[]);
+    let unit = estimate_unit_time_clustered(events, scratch, min_millis, max_millis)?;
+    decode_events(events, unit.item, text)?;
+    Ok(DecodedTransmission {
+        cutoffs,
+        polarity,
+        unit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::consts::*;
+
+    // Keys out `units` at `unit` samples each, alternating light and dark
+    fn helper_key(
+        intensities: &mut Vec<(Time, LightIntensity), U2048>,
+        units: &[Time],
+        unit: Time,
+        (dark, light): (LightIntensity, LightIntensity),
+    ) {
+        for (i, n) in units.iter().enumerate() {
+            let level = if i % 2 == 0 { light } else { dark };
+            for _ in 0..n * unit {
+                let time = intensities.len() as Time;
+                intensities.push((time, level)).unwrap();
+            }
+        }
+    }
+
+    fn helper_idle(intensities: &mut Vec<(Time, LightIntensity), U2048>, samples: Time) {
+        helper_key(intensities, &[0, samples], 1, (100, 900));
+    }
+
+    #[test]
+    fn test_split_and_decode() {
+        let sos = [1, 1, 1, 1, 1, 3, 3, 1, 3, 1, 3, 3, 1, 1, 1, 1, 1];
+        let te = [3, 3, 1];
+        let mut intensities = Vec::new();
+        helper_idle(&mut intensities, 300);
+        helper_key(&mut intensities, &sos, 10, (100, 900));
+        helper_idle(&mut intensities, 400);
+        // Dimmer and slower
+        helper_key(&mut intensities, &te, 25, (150, 500));
+        helper_idle(&mut intensities, 300);
+        // A single flash is noise, not a transmission
+        helper_key(&mut intensities, &[2], 10, (100, 900));
+        helper_idle(&mut intensities, 300);
+
+        let config = SplitConfig {
+            idle_gap: 200,
+            sample_gap: 10,
+            min_edges: 3,
+        };
+        let mut transmissions: Vec<Transmission, U4> = Vec::new();
+        split_transmissions(&intensities, &config, &mut transmissions).unwrap();
+        assert_eq!(2, transmissions.len());
+        assert_eq!((300, 570), (transmissions[0].start, transmissions[0].end));
+        assert_eq!(18, transmissions[0].edges);
+
+        let mut events: Vec<TimedLightEvent, U64> = Vec::new();
+        let mut scratch = Vec::new();
+        let mut texts: [String<U8>; 2] = [String::new(), String::new()];
+        let mut units = [0; 2];
+        for (i, t) in transmissions.iter().enumerate() {
+            let decoded = decode_transmission(
+                &intensities,
+                t,
+                &mut events,
+                &mut scratch,
+                (2, 100),
+                Polarity::Auto,
+                &mut texts[i],
+            )
+            .unwrap();
+            units[i] = decoded.unit.item;
+            assert_eq!(Polarity::Normal, decoded.polarity);
+        }
+        assert_eq!("SOS", texts[0].as_str());
+        assert_eq!("TE", texts[1].as_str());
+        assert_eq!([10, 25], units);
+    }
+
+    #[test]
+    fn test_split_on_signal_loss() {
+        let mut intensities = Vec::new();
+        helper_idle(&mut intensities, 50);
+        helper_key(&mut intensities, &[1, 1, 1, 1, 1], 10, (100, 900));
+        helper_idle(&mut intensities, 10);
+        // The sensor drops out for a while mid-message
+        let skip = intensities.len() as Time + 1000;
+        let mut resumed: Vec<(Time, LightIntensity), U2048> = Vec::new();
+        helper_key(&mut resumed, &[3, 1, 3, 1, 3, 10], 10, (100, 900));
+        for (time, light) in resumed.iter() {
+            intensities.push((time + skip, *light)).unwrap();
+        }
+
+        let config = SplitConfig {
+            idle_gap: 500,
+            sample_gap: 10,
+            min_edges: 3,
+        };
+        let mut transmissions: Vec<Transmission, U4> = Vec::new();
+        split_transmissions(&intensities, &config, &mut transmissions).unwrap();
+        assert_eq!(2, transmissions.len());
+        assert_eq!(skip, transmissions[1].start);
+    }
+}
@@ -0,0 +1,363 @@
+// Decoding one sample at a time, for receivers that can't buffer a capture.
+// Each decoder is a few dozen bytes, so the Uno can run one per sensor. Times
+// can be any `Timestamp`, including a `u16` or `u32` hardware counter that
+// wraps. A gap longer than the counter's period measures short once it
+// wraps, so once a gap has reached a word space the decoder counts as idle
+// and doesn't read anything from the gap's length when it ends.
+
+use crate::time::{TickCount, Timestamp};
+use crate::trie::TrieCursor;
+use crate::{
+    best_error_by, Absolute, LightIntensity, LightState, Morse, Time, TimedLightEvent, TimingModel,
+};
+use heapless::{ArrayLength, Vec};
+
+// Levels are tracked with 4 extra bits so slow drift isn't lost to rounding
+const LEVEL_SHIFT: u32 = 4;
+// Each new sample moves its level 1/16th of the way
+const LEVEL_RATE: u32 = 4;
+// Each new element moves the unit 1/4 of the way, and like the levels the
+// unit keeps 4 extra bits
+const UNIT_RATE: Time = 4;
+const UNIT_SHIFT: u32 = 4;
+
+#[derive(Copy, Clone, Debug)]
+pub struct StreamDecoder<'a, T = Time> {
+    model: TimingModel<'a>,
+    unit_fraction: Time,
+    min_contrast: i32,
+    dark_level: i32,
+    light_level: i32,
+    // Whether the levels have separated by `min_contrast` yet
+    locked: bool,
+    started: bool,
+    light_state: LightState,
+    // Set by the first sample
+    last_edge: Option<T>,
+    // The letter so far
+    code: TrieCursor,
+    mid_word: bool,
+    // Dark for at least a word space, or since the first sample
+    idle: bool,
+}
+
+impl<'a, T: Timestamp> StreamDecoder<'a, T> {
+    // `initial_unit` is in the ticks of `T`'s durations
+    pub fn new(
+        model: TimingModel<'a>,
+        initial_unit: T::Duration,
+        min_contrast: LightIntensity,
+    ) -> Self {
+        StreamDecoder {
+            model,
+            unit_fraction: initial_unit.ticks().max(1) << UNIT_SHIFT,
+            min_contrast: (min_contrast as i32) << LEVEL_SHIFT,
+            dark_level: 0,
+            light_level: 0,
+            locked: false,
+            started: false,
+            light_state: LightState::Dark,
+            last_edge: None,
+            code: TrieCursor::new(),
+            mid_word: false,
+            idle: true,
+        }
+    }
+
+    pub fn unit(&self) -> T::Duration {
+        T::Duration::from_ticks(self.unit_ticks())
+    }
+
+    fn unit_ticks(&self) -> Time {
+        (self.unit_fraction + (1 << (UNIT_SHIFT - 1))) >> UNIT_SHIFT
+    }
+
+    // The current (low, high) cutoffs, once there's been enough contrast
+    pub fn cutoffs(&self) -> Option<(LightIntensity, LightIntensity)> {
+        if !self.locked {
+            return None;
+        }
+        let diff = self.light_level - self.dark_level;
+        Some((
+            ((self.dark_level + diff / 4) >> LEVEL_SHIFT) as LightIntensity,
+            ((self.dark_level + 3 * diff / 4) >> LEVEL_SHIFT) as LightIntensity,
+        ))
+    }
+
+    fn classify(&self, light_state: LightState, duration: Time) -> Option<(Morse, Time)> {
+        let event = TimedLightEvent {
+            light_state,
+            duration,
+        };
+        let best = best_error_by(&event, self.unit_ticks(), &self.model, &Absolute).ok()?;
+        // What this event says the unit is, with the extra bits
+        let units = self.model.weighted_units(best.item).max(1);
+        let unit = (duration << UNIT_SHIFT) * self.model.subdivisions / units;
+        Some((self.model.morse(best.item), unit))
+    }
+
+    fn track_levels(&mut self, x: i32) {
+        if !self.started {
+            self.dark_level = x;
+            self.light_level = x;
+            self.started = true;
+        } else if !self.locked {
+            self.dark_level = self.dark_level.min(x);
+            self.light_level = self.light_level.max(x);
+            self.locked = self.light_level - self.dark_level >= self.min_contrast;
+        } else {
+            let level = match self.light_state {
+                LightState::Light => &mut self.light_level,
+                LightState::Dark => &mut self.dark_level,
+            };
+            *level += (x - *level) >> LEVEL_RATE;
+        }
+    }
+
+    fn flush(&mut self) -> Option<char> {
+        if self.code.is_empty() {
+            return None;
+        }
+        let c = self.code.char();
+        self.code.reset();
+        self.mid_word = true;
+        Some(c.unwrap_or('?'))
+    }
+
+    fn edge(&mut self, duration: Time, time: T) {
+        let ended = self.light_state;
+        self.last_edge = Some(time);
+        self.light_state = match ended {
+            LightState::Light => LightState::Dark,
+            LightState::Dark => LightState::Light,
+        };
+        if self.idle {
+            // The gap ending an idle could have wrapped any number of times
+            self.idle = false;
+            return;
+        }
+
+        let (morse, unit) = match self.classify(ended, duration) {
+            Some(classified) => classified,
+            None => return,
+        };
+        match morse {
+            Morse::Dot => {
+                self.code.push(Morse::Dot);
+            }
+            Morse::Dash | Morse::LongDash => {
+                self.code.push(Morse::Dash);
+            }
+            Morse::TinySpace | Morse::InnerSpace => (),
+            // Longer gaps were already acted on as they went by, and only
+            // say the unit is somewhere below them
+            _ => return,
+        }
+        self.unit_fraction += (unit - self.unit_fraction) / UNIT_RATE;
+        self.unit_fraction = self.unit_fraction.max(1 << UNIT_SHIFT);
+    }
+
+    // Feeds in the next sample, returning a letter once the gap after it is
+    // long enough to end it, and a space once the gap ends the word
+    pub fn push(&mut self, time: T, intensity: LightIntensity) -> Option<char> {
+        self.track_levels((intensity as i32) << LEVEL_SHIFT);
+        let elapsed = time.since(*self.last_edge.get_or_insert(time)).ticks();
+        let (low, high) = self.cutoffs()?;
+        match self.light_state {
+            LightState::Dark if intensity > high => self.edge(elapsed, time),
+            LightState::Light if intensity < low => self.edge(elapsed, time),
+            _ => (),
+        }
+        if self.light_state == LightState::Light {
+            return None;
+        }
+
+        let gap = self
+            .classify(LightState::Dark, time.since(self.last_edge?).ticks())
+            .map(|g| g.0);
+        if gap == Some(Morse::WordSpace) {
+            self.idle = true;
+        }
+        match gap {
+            Some(Morse::LetterSpace) | Some(Morse::WordSpace) if !self.code.is_empty() => {
+                self.flush()
+            }
+            Some(Morse::WordSpace) if self.mid_word => {
+                self.mid_word = false;
+                Some(' ')
+            }
+            _ => None,
+        }
+    }
+
+    // The letter still in progress at the end of a capture
+    pub fn finish(&mut self) -> Option<char> {
+        self.flush()
+    }
+}
+
+// Independent decoders for several sources sampled together, like LEDs on
+// different ADC pins. `N` is the number of channels, e.g. `U2`.
+pub struct MultiDecoder<'a, N, T = Time>
+where
+    N: ArrayLength<StreamDecoder<'a, T>>,
+{
+    decoders: Vec<StreamDecoder<'a, T>, N>,
+}
+
+impl<'a, N, T> MultiDecoder<'a, N, T>
+where
+    N: ArrayLength<StreamDecoder<'a, T>>,
+    T: Timestamp,
+{
+    pub fn new(
+        model: TimingModel<'a>,
+        initial_unit: T::Duration,
+        min_contrast: LightIntensity,
+    ) -> Self {
+        let decoder = StreamDecoder::new(model, initial_unit, min_contrast);
+        let mut decoders = Vec::new();
+        while decoders.push(decoder).is_ok() {}
+        MultiDecoder { decoders }
+    }
+
+    pub fn channel(&self, channel: usize) -> &StreamDecoder<'a, T> {
+        &self.decoders[channel]
+    }
+
+    // Feeds a sample tagged with the channel it came from, returning any
+    // output tagged the same way
+    pub fn push(
+        &mut self,
+        channel: usize,
+        time: T,
+        intensity: LightIntensity,
+    ) -> Option<(usize, char)> {
+        let decoder = self.decoders.get_mut(channel)?;
+        decoder.push(time, intensity).map(|c| (channel, c))
+    }
+
+    pub fn finish(&mut self, channel: usize) -> Option<char> {
+        self.decoders.get_mut(channel)?.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::consts::*;
+    use heapless::{String, Vec};
+
+    // Samples keying out `text` at `unit` per sample step, between `levels`
+    fn helper_key(text: &str, unit: Time, levels: (u16, u16)) -> Vec<u16, U4096> {
+        let mut units: Vec<(bool, Time), U512> = Vec::new();
+        units.push((false, 10)).unwrap();
+        for word in text.split(' ') {
+            for letter in word.chars() {
+                let code = crate::MORSE_CODES
+                    .iter()
+                    .find(|(_, c)| *c == letter)
+                    .unwrap()
+                    .0;
+                for element in code.bytes() {
+                    units
+                        .push((true, if element == b'.' { 1 } else { 3 }))
+                        .unwrap();
+                    units.push((false, 1)).unwrap();
+                }
+                units.last_mut().unwrap().1 = 3;
+            }
+            units.last_mut().unwrap().1 = 7;
+        }
+        units.push((false, 10)).unwrap();
+
+        let mut samples = Vec::new();
+        for (light, n) in units.iter() {
+            for _ in 0..n * unit {
+                samples
+                    .push(if *light { levels.1 } else { levels.0 })
+                    .unwrap();
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn test_stream_adapts_unit() {
+        let samples = helper_key("PARIS PARIS", 10, (200, 800));
+        let mut decoder = StreamDecoder::new(TimingModel::standard(), 14, 100);
+        let mut text: String<U16> = String::new();
+        for (time, li) in samples.iter().enumerate() {
+            if let Some(c) = decoder.push(time as Time, *li) {
+                text.push(c).unwrap();
+            }
+        }
+        assert_eq!(None, decoder.finish());
+        assert_eq!("PARIS PARIS ", text.as_str());
+        assert_eq!(10, decoder.unit());
+    }
+
+    #[test]
+    fn test_stream_across_wrap() {
+        // Timestamps from a 16-bit counter that rolls over partway through
+        let samples = helper_key("PARIS PARIS", 10, (200, 800));
+        let start = u16::MAX - 600;
+        let mut decoder = StreamDecoder::new(TimingModel::standard(), 10, 100);
+        let mut text: String<U16> = String::new();
+        for (i, li) in samples.iter().enumerate() {
+            if let Some(c) = decoder.push(start.wrapping_add(i as u16), *li) {
+                text.push(c).unwrap();
+            }
+        }
+        assert!(start.checked_add(samples.len() as u16).is_none());
+        assert_eq!("PARIS PARIS ", text.as_str());
+        assert_eq!(10, decoder.unit());
+    }
+
+    #[test]
+    fn test_stream_idle_past_wrap() {
+        // Dark for over two periods of a 16-bit counter between words, with
+        // the whole gap wrapping to what looks like a long inner space
+        let samples = helper_key("PARIS", 10, (200, 800));
+        // Less the 170 dark samples that end a capture and the 100 that start
+        // one
+        let idle = 2 * (1 << 16) - 270 + 15;
+        let mut decoder = StreamDecoder::new(TimingModel::standard(), 10, 100);
+        let mut text: String<U16> = String::new();
+        let mut time: u16 = 0;
+        let keyed = samples
+            .iter()
+            .chain(core::iter::repeat(&200).take(idle))
+            .chain(samples.iter());
+        for li in keyed {
+            if let Some(c) = decoder.push(time, *li) {
+                text.push(c).unwrap();
+            }
+            assert_eq!(10, decoder.unit());
+            time = time.wrapping_add(1);
+        }
+        assert_eq!("PARIS PARIS ", text.as_str());
+    }
+
+    #[test]
+    fn test_multi_stream() {
+        // Two lamps at different speeds and brightness, sampled in turn
+        let first = helper_key("SOS", 10, (100, 900));
+        let second = helper_key("CQ DE", 17, (400, 600));
+        let mut decoder: MultiDecoder<U2> = MultiDecoder::new(TimingModel::standard(), 12, 100);
+        let mut texts: [String<U16>; 2] = [String::new(), String::new()];
+        for time in 0..first.len().max(second.len()) {
+            for (channel, samples) in [&first, &second].iter().enumerate() {
+                // The shorter capture sits idle once it's done
+                let li = samples.get(time).unwrap_or(&samples[0]);
+                if let Some((channel, c)) = decoder.push(channel, time as Time, *li) {
+                    texts[channel].push(c).unwrap();
+                }
+            }
+        }
+        assert_eq!("SOS ", texts[0].as_str());
+        assert_eq!("CQ DE ", texts[1].as_str());
+        assert_eq!(17, decoder.channel(1).unit());
+        assert_eq!(None, decoder.push(2, 0, 0));
+    }
+}
@@ -0,0 +1,296 @@
+// Durations tagged with the rate they were counted at. A bare `Time` is
+// milliseconds in some captures, 5ms sample steps in others and plain sample
+// indices in the rest, so these carry the rate in the type instead.
+//
+// Everything that takes or makes event durations takes anything that's a
+// `TickCount`, bare `Time` included, and works in whatever ticks it's given:
+// `convert` and its variants, scoring, the unit estimators, `fit_timing`, the
+// edge bias helpers, segmenting, quality and the decoders. Sample times stay
+// bare `Time`, except in `iter` and the stream decoder, which take any
+// `Timestamp` and type their events and unit by its `Duration`. The
+// arithmetic is done in `Time` either way, so a narrower count only saves
+// space in stored events, not 64-bit math. For that, `compact` has the unit
+// search and decoder in 16-bit durations and 32-bit scores.
+
+use crate::Time;
+use core::ops::{Add, Sub};
+
+pub trait TickCount: Copy {
+    fn ticks(self) -> Time;
+    fn from_ticks(ticks: Time) -> Self;
+}
+
+impl TickCount for Time {
+    fn ticks(self) -> Time {
+        self
+    }
+
+    fn from_ticks(ticks: Time) -> Self {
+        ticks
+    }
+}
+
+// Nearest tick at `to` Hz for `ticks` at `from` Hz
+fn rescale(ticks: Time, from: u32, to: u32) -> Time {
+    let (from, to) = (from as Time, to as Time);
+    (2 * ticks * to + from).div_euclid(2 * from)
+}
+
+// A duration of `HZ` ticks per second, e.g. `Ticks<30>` for frames of a 30fps
+// video
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug, Default)]
+pub struct Ticks<const HZ: u32>(pub Time);
+
+pub type Millis = Ticks<1000>;
+pub type Micros = Ticks<1_000_000>;
+
+impl<const HZ: u32> Ticks<HZ> {
+    pub const fn new(ticks: Time) -> Self {
+        Ticks(ticks)
+    }
+
+    pub fn convert<const TO: u32>(self) -> Ticks<TO> {
+        Ticks(rescale(self.0, HZ, TO))
+    }
+}
+
+impl<const HZ: u32> TickCount for Ticks<HZ> {
+    fn ticks(self) -> Time {
+        self.0
+    }
+
+    fn from_ticks(ticks: Time) -> Self {
+        Ticks(ticks)
+    }
+}
+
+impl<const HZ: u32> Add for Ticks<HZ> {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Ticks(self.0 + other.0)
+    }
+}
+
+impl<const HZ: u32> Sub for Ticks<HZ> {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Ticks(self.0 - other.0)
+    }
+}
+
+// A point in time at `HZ`, counted from whenever the clock started
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug, Default)]
+pub struct Instant<const HZ: u32>(pub Time);
+
+impl<const HZ: u32> Sub for Instant<HZ> {
+    type Output = Ticks<HZ>;
+
+    fn sub(self, earlier: Self) -> Ticks<HZ> {
+        Ticks(self.0 - earlier.0)
+    }
+}
+
+impl<const HZ: u32> Add<Ticks<HZ>> for Instant<HZ> {
+    type Output = Self;
+
+    fn add(self, duration: Ticks<HZ>) -> Self {
+        Instant(self.0 + duration.0)
+    }
+}
+
+// Half the size of `Ticks` in stored events, for durations that are never
+// negative. Out of range values saturate rather than wrap.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug, Default)]
+pub struct CompactTicks<const HZ: u32>(pub u32);
+
+pub type CompactMillis = CompactTicks<1000>;
+
+impl<const HZ: u32> TickCount for CompactTicks<HZ> {
+    fn ticks(self) -> Time {
+        self.0 as Time
+    }
+
+    fn from_ticks(ticks: Time) -> Self {
+        CompactTicks(ticks.clamp(0, u32::MAX as Time) as u32)
+    }
+}
+
+impl<const HZ: u32> From<Ticks<HZ>> for CompactTicks<HZ> {
+    fn from(ticks: Ticks<HZ>) -> Self {
+        CompactTicks::from_ticks(ticks.0)
+    }
+}
+
+impl<const HZ: u32> From<CompactTicks<HZ>> for Ticks<HZ> {
+    fn from(ticks: CompactTicks<HZ>) -> Self {
+        Ticks(ticks.0 as Time)
+    }
+}
+
+// A reading of a clock, which can be a hardware counter that wraps. Durations
+// come out right across the wrap as long as none is longer than a full cycle.
+pub trait Timestamp: Copy {
+    type Duration: TickCount;
+
+    fn since(self, earlier: Self) -> Self::Duration;
+}
+
+impl Timestamp for Time {
+    type Duration = Time;
+
+    fn since(self, earlier: Self) -> Time {
+        self - earlier
+    }
+}
+
+impl Timestamp for u32 {
+    type Duration = Time;
+
+    fn since(self, earlier: Self) -> Time {
+        self.wrapping_sub(earlier) as Time
+    }
+}
+
+impl Timestamp for u16 {
+    type Duration = Time;
+
+    fn since(self, earlier: Self) -> Time {
+        self.wrapping_sub(earlier) as Time
+    }
+}
+
+impl<const HZ: u32> Timestamp for Instant<HZ> {
+    type Duration = Ticks<HZ>;
+
+    fn since(self, earlier: Self) -> Ticks<HZ> {
+        self - earlier
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iter::SampleIterator;
+    use crate::quality::analyze;
+    use crate::stream::StreamDecoder;
+    use crate::{
+        calc_digital_cutoffs_by, convert, decode_events, estimate_unit_time,
+        estimate_unit_time_clustered, fit_timing, LightIntensity, LightState, ThresholdMethod,
+        TimedLightEvent, TimingModel,
+    };
+    use heapless::consts::*;
+    use heapless::{String, Vec};
+
+    #[test]
+    fn test_convert() {
+        let frames: Ticks<30> = Ticks(7);
+        assert_eq!(Millis::from_ticks(233), frames.convert());
+        assert_eq!(Ticks::<30>(7), Millis::new(233).convert());
+        assert_eq!(
+            Micros::new(1_500),
+            Millis::new(2).convert::<1_000_000>() - Micros::new(500)
+        );
+        assert_eq!(Millis::new(-3), Micros::new(-2_600).convert());
+        assert_eq!(Millis::new(40), Instant::<1000>(140) - Instant(100));
+    }
+
+    #[test]
+    fn test_compact_saturates() {
+        assert_eq!(CompactMillis::from(Millis::new(-5)), CompactTicks(0));
+        assert_eq!(
+            CompactMillis::from(Millis::new(1 << 40)),
+            CompactTicks(u32::MAX)
+        );
+        assert_eq!(Millis::new(77), CompactTicks(77).into());
+    }
+
+    #[test]
+    fn test_since_wraps() {
+        assert_eq!(30, 10u16.since(65516));
+        assert_eq!(30, 10u32.since(u32::MAX - 19));
+        assert_eq!(-30, 10i64.since(40));
+    }
+
+    #[test]
+    fn test_decode_typed() {
+        // "TE" sampled at 30Hz, decoded without leaving frames, then again
+        // from the same events in compact milliseconds
+        let durations = [9, 9, 3];
+        let mut frames: Vec<TimedLightEvent<Ticks<30>>, U8> = Vec::new();
+        let mut compact: Vec<TimedLightEvent<CompactMillis>, U8> = Vec::new();
+        for (i, duration) in durations.iter().enumerate() {
+            let light_state = if i % 2 == 0 {
+                LightState::Light
+            } else {
+                LightState::Dark
+            };
+            let duration = Ticks(*duration);
+            frames
+                .push(TimedLightEvent {
+                    light_state,
+                    duration,
+                })
+                .unwrap();
+            compact
+                .push(TimedLightEvent {
+                    light_state,
+                    duration: duration.convert::<1000>().into(),
+                })
+                .unwrap();
+        }
+
+        let unit = estimate_unit_time(&frames, Ticks(1), Ticks(10)).unwrap();
+        assert_eq!(Ticks(3), unit.item);
+        let mut text: String<U8> = String::new();
+        decode_events(&frames, unit.item, &mut text).unwrap();
+        assert_eq!("TE", text.as_str());
+
+        let unit = estimate_unit_time(&compact, CompactTicks(10), CompactTicks(300)).unwrap();
+        assert_eq!(CompactTicks(100), unit.item);
+        let mut text: String<U8> = String::new();
+        decode_events(&compact, unit.item, &mut text).unwrap();
+        assert_eq!("TE", text.as_str());
+    }
+
+    #[test]
+    fn test_typed_pipeline() {
+        // "TE" at 3 frames a unit, with frame numbers for times
+        let units = [(false, 4), (true, 3), (false, 3), (true, 1), (false, 8)];
+        let mut samples: Vec<(Time, LightIntensity), U128> = Vec::new();
+        for (light, n) in units.iter() {
+            for _ in 0..n * 3 {
+                let level = if *light { 900 } else { 100 };
+                samples.push((samples.len() as Time, level)).unwrap();
+            }
+        }
+
+        let mut events: Vec<TimedLightEvent<Ticks<30>>, U8> = Vec::new();
+        convert(&samples, &mut events, 0).unwrap();
+        assert_eq!(Ticks(12), events[0].duration);
+        let events = &events[1..];
+        let mut scratch: Vec<TimedLightEvent<Ticks<30>>, U8> = Vec::new();
+        let unit = estimate_unit_time_clustered(events, &mut scratch, Ticks(1), Ticks(10)).unwrap();
+        assert_eq!(Ticks(3), unit.item);
+        assert_eq!(Ticks(3), fit_timing(events, unit.item).unwrap().unit.item);
+        let cutoffs = calc_digital_cutoffs_by(&samples, ThresholdMethod::Otsu).unwrap();
+        let report = analyze(&cutoffs, events, unit.item, &TimingModel::standard());
+        assert_eq!(3, report.unit_millis);
+
+        // The same frames as instants, straight into the iterators and the
+        // stream decoder
+        let instants = samples.iter().map(|(time, li)| (Instant::<30>(*time), *li));
+        let first = instants.clone().light_events(cutoffs.pair()).next();
+        assert_eq!(Some(Ticks(12)), first.map(|e| e.duration));
+        let mut decoder = StreamDecoder::new(TimingModel::standard(), Ticks(3), 100);
+        let mut text: String<U8> = String::new();
+        for (time, li) in instants {
+            if let Some(c) = decoder.push(time, li) {
+                text.push(c).unwrap();
+            }
+        }
+        assert_eq!("TE", text.trim_end());
+        assert_eq!(Ticks::<30>(3), decoder.unit());
+    }
+}
@@ -0,0 +1,160 @@
+// The standard code table as a binary tree walked from the root, left for a
+// dot and right for a dash. The tree is stored heap style, children of node
+// `i` at `2i` and `2i + 1`, so it's a flat 256 byte array built at compile
+// time. On AVR it stays in flash rather than being copied into the Uno's 2K of
+// RAM, and a letter in progress is a single byte.
+
+use crate::{Morse, MORSE_CODES};
+
+// Deep enough for the longest code, '$' at 7 elements
+const DEPTH: usize = 7;
+const NODES: usize = 2 << DEPTH;
+const ROOT: u8 = 1;
+// Where a code ends up once it can't be any letter
+const DEAD: u8 = 0;
+
+const fn build(codes: &[(&str, char)]) -> [u8; NODES] {
+    let mut nodes = [0; NODES];
+    let mut i = 0;
+    while i < codes.len() {
+        let (code, c) = codes[i];
+        let code = code.as_bytes();
+        let mut node = ROOT as usize;
+        let mut j = 0;
+        while j < code.len() {
+            node = 2 * node + (code[j] == b'-') as usize;
+            j += 1;
+        }
+        nodes[node] = c as u8;
+        i += 1;
+    }
+    nodes
+}
+
+#[cfg_attr(target_arch = "avr", link_section = ".progmem.data")]
+static MORSE_TRIE: [u8; NODES] = build(&MORSE_CODES);
+
+#[cfg(target_arch = "avr")]
+fn node_char(node: u8) -> u8 {
+    // Flash isn't in the data address space, so it has to be read with lpm,
+    // the same way avr-libc's pgm_read_byte does
+    let c: u8;
+    unsafe {
+        core::arch::asm!(
+            "lpm {}, Z",
+            out(reg) c,
+            in("Z") MORSE_TRIE.as_ptr().add(node as usize),
+            options(pure, readonly, nostack, preserves_flags),
+        );
+    }
+    c
+}
+
+#[cfg(not(target_arch = "avr"))]
+fn node_char(node: u8) -> u8 {
+    MORSE_TRIE[node as usize]
+}
+
+// A letter being looked up one element at a time, as each arrives
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct TrieCursor {
+    node: u8,
+}
+
+impl TrieCursor {
+    pub const fn new() -> Self {
+        TrieCursor { node: ROOT }
+    }
+
+    // Anything but a dot or dash, or more elements than any code has, means
+    // the letter can't be decoded
+    pub fn push(&mut self, element: Morse) -> &mut Self {
+        self.node = match (self.node, element) {
+            (node, _) if node == DEAD || node as usize >= NODES / 2 => DEAD,
+            (node, Morse::Dot) => 2 * node,
+            (node, Morse::Dash) => 2 * node + 1,
+            _ => DEAD,
+        };
+        self
+    }
+
+    // The letter the elements so far spell, if they're a whole code
+    pub fn char(&self) -> Option<char> {
+        match node_char(self.node) {
+            0 => None,
+            c => Some(c as char),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.node == ROOT
+    }
+
+    pub fn reset(&mut self) {
+        self.node = ROOT;
+    }
+}
+
+impl Default for TrieCursor {
+    fn default() -> Self {
+        TrieCursor::new()
+    }
+}
+
+// The letter for a whole code, the same as `CodeTable::standard().lookup`
+pub fn lookup(code: &[Morse]) -> Option<char> {
+    let mut cursor = TrieCursor::new();
+    for element in code {
+        cursor.push(*element);
+    }
+    cursor.char()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CodeTable;
+    use Morse::*;
+
+    #[test]
+    fn test_matches_code_table() {
+        let table = CodeTable::standard();
+        for (pattern, c) in MORSE_CODES.iter() {
+            let mut code = [Dot; DEPTH];
+            for (element, b) in code.iter_mut().zip(pattern.bytes()) {
+                if b == b'-' {
+                    *element = Dash;
+                }
+            }
+            assert_eq!(Some(*c), lookup(&code[..pattern.len()]));
+        }
+        // Every code up to 8 elements, known or not
+        for len in 0..=DEPTH + 1 {
+            for bits in 0..1 << len {
+                let mut code = [Dot; DEPTH + 1];
+                for (i, element) in code.iter_mut().enumerate().take(len) {
+                    if bits & (1 << i) != 0 {
+                        *element = Dash;
+                    }
+                }
+                let code = &code[..len];
+                assert_eq!(table.lookup(code), lookup(code), "{:?}", code);
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental() {
+        let mut cursor = TrieCursor::new();
+        assert!(cursor.is_empty());
+        assert_eq!(Some('S'), cursor.push(Dot).push(Dot).push(Dot).char());
+        assert_eq!(Some('V'), cursor.push(Dash).char());
+        // Not a letter, but on the way to '$'
+        assert_eq!(None, cursor.push(Dot).push(Dot).char());
+        assert_eq!(Some('$'), cursor.push(Dash).char());
+        assert_eq!(None, cursor.push(Dot).char());
+        cursor.reset();
+        assert_eq!(None, cursor.push(LongDash).push(Dot).char());
+        assert!(!cursor.is_empty());
+    }
+}
@@ -26,8 +26,70 @@ fn stutter_blink(led: &mut PB5<Output>, times: i16) {
     arduino_uno::delay_ms(1000);
 }
 
+use morse_utils::stream::MultiDecoder;
+use morse_utils::quality::{analyze, detect_morse, SignalReport, DEFAULT_SQUELCH};
 use morse_utils::*;
 
+fn morse_name(morse: Morse) -> &'static str {
+    match morse {
+        Morse::Dot => "Dot",
+        Morse::Dash => "Dash",
+        Morse::LongDash => "LongDash",
+        Morse::TinySpace => "TinySpace",
+        Morse::InnerSpace => "InnerSpace",
+        Morse::LetterSpace => "LetterSpace",
+        Morse::WordSpace => "WordSpace",
+        Morse::Error => "Error",
+    }
+}
+
+// Writes `x` hundredths or tenths with its decimal point, as the host's
+// report does. ufmt has no padding, so a leading zero is added by hand.
+fn print_fixed<W: ufmt::uWrite>(serial: &mut W, x: i64, scale: i64) -> Result<(), W::Error> {
+    let sign = if x < 0 { "-" } else { "" };
+    let x = x.abs();
+    let pad = if scale == 100 && x % 100 < 10 { "0" } else { "" };
+    ufmt::uwrite!(serial, "{}{}.{}{}", sign, x / scale, pad, x % scale)
+}
+
+// Same format as the report's Display on the host, without pulling in
+// core::fmt
+fn print_report<W: ufmt::uWrite>(serial: &mut W, report: &SignalReport) -> Result<(), W::Error> {
+    ufmt::uwriteln!(
+        serial,
+        "levels: dark {} +/- {}, light {} +/- {}\r",
+        report.dark_level,
+        report.dark_noise,
+        report.light_level,
+        report.light_noise
+    )?;
+    ufmt::uwrite!(serial, "contrast: ")?;
+    print_fixed(serial, report.contrast, 100)?;
+    ufmt::uwrite!(serial, ":1, snr: ")?;
+    print_fixed(serial, report.snr, 10)?;
+    ufmt::uwriteln!(serial, "dB\r")?;
+    ufmt::uwrite!(
+        serial,
+        "unit: {}, {} of {} events fit poorly (",
+        report.unit_millis,
+        report.poor_fits,
+        report.events
+    )?;
+    print_fixed(serial, report.poor_fit_permille(), 10)?;
+    ufmt::uwriteln!(serial, "%)\r")?;
+    for d in report.durations.iter() {
+        ufmt::uwriteln!(
+            serial,
+            "{}: {} x {} +/- {}\r",
+            morse_name(d.morse),
+            d.count,
+            d.mean,
+            d.stddev
+        )?;
+    }
+    Ok(())
+}
+
 fn helper_fill_events_slice<T>(durations: &[i64], vec: &mut Vec<TimedLightEvent, T>)
 where
     T: heapless::ArrayLength<TimedLightEvent>,
@@ -116,6 +178,12 @@ fn main() -> ! {
     let mut pins = arduino_uno::Pins::new(peripherals.PORTB, peripherals.PORTC, peripherals.PORTD);
 
     let mut led = pins.d13.into_output(&mut pins.ddr);
+    let mut serial = arduino_uno::Serial::new(
+        peripherals.USART0,
+        pins.d0,
+        pins.d1.into_output(&mut pins.ddr),
+        57600.into_baudrate(),
+    );
 
     // stutter_blink(&mut led, 1);
     // arduino_uno::delay_ms(1000);
@@ -129,12 +197,47 @@ fn main() -> ! {
 
     // stutter_blink(&mut led, 1);
 
-    let expected: Scored<i64> = Scored {
+    // The 16-bit search is cheap enough to cover every plausible speed
+    // instead of only 100..110
+    let compact_events: Vec<TimedLightEvent<u16>, U64> = timed_light_events
+        .iter()
+        .map(|e| TimedLightEvent {
+            light_state: e.light_state,
+            duration: e.duration as u16,
+        })
+        .collect();
+    let expected: Scored<u16> = Scored {
         item: 100,
         score: 0,
     };
-    match estimate_unit_time(&timed_light_events, 100, 110) {
+    match compact::estimate_unit_time(&compact_events, 20, 400) {
         Ok(actual) if expected == actual => {
+            // The squelch and report both judge the sample capture, split
+            // into events at the same cutoffs they report on
+            let cutoffs = calc_digital_cutoffs_by(&myint, ThresholdMethod::Otsu);
+            let mut capture: Vec<TimedLightEvent, U32> = Vec::new();
+            let converted = cutoffs.and_then(|cutoffs| {
+                convert_with_cutoffs(&myint, &mut capture, myint[0].0, cutoffs.pair(), None)
+            });
+            // The first event is the wait before the light first comes on
+            let capture_events = capture.get(1..).unwrap_or(&[]);
+            let capture_unit = estimate_unit_time(capture_events, 1, 20);
+            if let (Ok(cutoffs), Ok(()), Ok(capture_unit)) = (cutoffs, converted, capture_unit) {
+                let model = TimingModel::standard();
+                let detection = detect_morse(&cutoffs, capture_events, capture_unit.item, &model);
+                if detection.is_morse(DEFAULT_SQUELCH) {
+                    let report = analyze(&cutoffs, capture_events, capture_unit.item, &model);
+                    print_report(&mut serial, &report).void_unwrap();
+                } else {
+                    // Not worth printing a decode of a flickering lamp
+                    ufmt::uwriteln!(
+                        &mut serial,
+                        "squelched: {}% sure this is Morse\r",
+                        detection.confidence
+                    )
+                    .void_unwrap();
+                }
+            }
         },
         Err(_) => loop {
             stutter_blink(&mut led, 5);
@@ -301,8 +404,25 @@ fn main() -> ! {
     //         arduino_uno::delay_ms(1000);
     //     },
     // };
+    // Receive from two sensors at once, printing each letter tagged with the
+    // pin it came in on
+    let mut adc = arduino_uno::adc::Adc::new(peripherals.ADC, Default::default());
+    let mut a0 = pins.a0.into_analog_input(&mut adc);
+    let mut a1 = pins.a1.into_analog_input(&mut adc);
+    // A 16-bit count wraps every minute or so, which the decoders handle
+    let mut decoder: MultiDecoder<U2, u16> = MultiDecoder::new(TimingModel::standard(), 100, 50);
+    let mut millis: u16 = 0;
     loop {
-            stutter_blink(&mut led, 9);
-            arduino_uno::delay_ms(1000);
+        let levels: [u16; 2] = [
+            nb::block!(adc.read(&mut a0)).void_unwrap(),
+            nb::block!(adc.read(&mut a1)).void_unwrap(),
+        ];
+        for (channel, level) in levels.iter().enumerate() {
+            if let Some((channel, c)) = decoder.push(channel, millis, *level) {
+                ufmt::uwriteln!(&mut serial, "A{}: {}\r", channel, c).void_unwrap();
+            }
+        }
+        arduino_uno::delay_ms(1);
+        millis = millis.wrapping_add(1);
     }
 }